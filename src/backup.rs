@@ -16,117 +16,749 @@ GNU General Public License for more details.
 You should have received a copy of the GNU General Public License
 along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
 */
+use std::collections::{hash_map::Entry, HashMap};
+use std::fmt;
 use std::fs::{File, Metadata};
-use std::io::ErrorKind;
-use std::path::Path;
-use std::sync::Mutex;
+use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult, Seek, SeekFrom};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Mutex, OnceLock,
+};
+use std::time::{Duration, Instant};
 
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use serde::{
+    de::{self, value::MapAccessDeserializer, MapAccess, Visitor},
+    Deserialize, Deserializer,
+};
 
 use super::{
     client::Client,
-    manifest::{store_block, Update},
-    split::split,
-    was_interrupted, Config, Fallible,
+    interrupt,
+    manifest::{store_block, BlockIndex, Update},
+    split::{data_extents, split},
+    was_interrupted,
+    xattr::{read_xattrs, Xattrs},
+    Bytes, Config, Fallible,
 };
 
-macro_rules! try_not_found {
-    ($res:expr) => {
+macro_rules! try_io {
+    ($backup:expr, $path:expr, $res:expr) => {
         match $res {
             Ok(val) => val,
             Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
-            Err(err) => return Err(err.into()),
+            Err(err) => return $backup.skip($path, err),
         }
     };
 }
 
-pub fn backup(config: &Config, client: &Client, update: &Mutex<Update>, path: &Path) -> Fallible {
-    if was_interrupted() {
-        return Ok(());
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorPolicy {
+    Skip,
+    Abort,
+}
+
+#[derive(Debug)]
+pub enum Include {
+    Path(PathBuf),
+    Options(IncludeOptions),
+}
+
+impl<'de> Deserialize<'de> for Include {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct IncludeVisitor;
+
+        impl<'de> Visitor<'de> for IncludeVisitor {
+            type Value = Include;
+
+            fn expecting(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+                fmt.write_str("a path or include options")
+            }
+
+            fn visit_str<E: de::Error>(self, path: &str) -> Result<Include, E> {
+                Ok(Include::Path(path.into()))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Include, A::Error> {
+                IncludeOptions::deserialize(MapAccessDeserializer::new(map)).map(Include::Options)
+            }
+        }
+
+        deserializer.deserialize_any(IncludeVisitor)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IncludeOptions {
+    path: PathBuf,
+    #[serde(default)]
+    excludes: Vec<PathBuf>,
+    keep_deleted_files: Option<bool>,
+    compression_level: Option<i32>,
+    one_file_system: Option<bool>,
+    chunk_bits: Option<u32>,
+}
+
+impl Include {
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::Path(path) => path,
+            Self::Options(options) => &options.path,
+        }
     }
 
-    if let Some(exclude) = config
-        .excludes
-        .iter()
-        .find(|exclude| path.starts_with(exclude))
-    {
-        println!(
-            "Skipping {} due to exclude {}",
-            path.display(),
-            exclude.display(),
-        );
-        return Ok(());
-    }
-
-    let metadata = try_not_found!(path.symlink_metadata());
-    let file_type = metadata.file_type();
-
-    if file_type.is_dir() {
-        backup_dir(config, client, update, path, &metadata)?;
-    } else if file_type.is_file() {
-        backup_file(config, client, update, path, &metadata)?;
-    } else if file_type.is_symlink() {
-        backup_symlink(update, path)?;
-    } else {
-        eprintln!(
-            "Skipping {} as it does not appear to be a regular file",
-            path.display()
-        );
+    fn options(&self) -> Option<&IncludeOptions> {
+        match self {
+            Self::Path(_) => None,
+            Self::Options(options) => Some(options),
+        }
+    }
+
+    fn excludes(&self) -> &[PathBuf] {
+        self.options().map_or(&[], |options| &options.excludes)
+    }
+
+    pub fn keep_deleted_files(&self, config: &Config) -> bool {
+        self.options()
+            .and_then(|options| options.keep_deleted_files)
+            .unwrap_or(config.keep_deleted_files)
     }
 
-    Ok(())
+    fn compression_level(&self, config: &Config) -> i32 {
+        self.options()
+            .and_then(|options| options.compression_level)
+            .unwrap_or(config.compression_level)
+    }
+
+    fn one_file_system(&self, config: &Config) -> bool {
+        self.options()
+            .and_then(|options| options.one_file_system)
+            .unwrap_or(config.one_file_system)
+    }
+
+    pub fn chunk_bits(&self, config: &Config) -> u32 {
+        self.options()
+            .and_then(|options| options.chunk_bits)
+            .unwrap_or(config.chunk_bits)
+    }
 }
 
-fn backup_dir(
-    config: &Config,
-    client: &Client,
-    update: &Mutex<Update>,
-    path: &Path,
-    metadata: &Metadata,
-) -> Fallible {
-    let dir = try_not_found!(path.read_dir());
+#[derive(Default)]
+pub struct Summary {
+    errors: Vec<(PathBuf, IoError)>,
+    marked_dirs: Vec<PathBuf>,
+    remaining: Option<(u64, u64)>,
+    estimate_stopped: bool,
+}
 
-    update.lock().unwrap().directory(path, metadata)?;
+impl Summary {
+    pub fn has_warnings(&self) -> bool {
+        !self.errors.is_empty()
+    }
 
-    let paths = dir
-        .map(|entry| entry.map(|entry| entry.path()))
-        .collect::<Result<Vec<_>, _>>()?;
+    pub fn print(&self) {
+        if !self.marked_dirs.is_empty() {
+            println!(
+                "Skipped {} directories due to marker files:",
+                self.marked_dirs.len()
+            );
 
-    paths
-        .par_iter()
-        .try_for_each(|path| backup(config, client, update, path))
+            for path in &self.marked_dirs {
+                println!("  {}", path.display());
+            }
+        }
+
+        if !self.errors.is_empty() {
+            println!("Skipped {} paths due to errors:", self.errors.len());
+
+            for (path, err) in &self.errors {
+                println!("  {}: {}", path.display(), err);
+            }
+        }
+
+        if let Some((files, size)) = self.remaining {
+            println!(
+                "Left {} {} in {} files for the next backup",
+                if self.estimate_stopped {
+                    "at least"
+                } else {
+                    "approximately"
+                },
+                Bytes(size as _),
+                files
+            );
+        }
+    }
+}
+
+pub struct Budget {
+    max_upload: Option<u64>,
+    deadline: Option<Instant>,
+    exhausted: AtomicBool,
+    estimate_deadline: OnceLock<Instant>,
 }
 
-fn backup_file(
-    config: &Config,
-    client: &Client,
-    update: &Mutex<Update>,
-    path: &Path,
-    metadata: &Metadata,
-) -> Fallible {
-    let file = try_not_found!(File::open(path));
+impl Budget {
+    pub fn new(max_upload: Option<u64>, max_duration: Option<Duration>) -> Self {
+        Self {
+            max_upload,
+            deadline: max_duration
+                .and_then(|max_duration| Instant::now().checked_add(max_duration)),
+            exhausted: AtomicBool::new(false),
+            estimate_deadline: OnceLock::new(),
+        }
+    }
+
+    fn check(&self, client: &Client, summary: &Mutex<Summary>) -> bool {
+        if self.exhausted.load(Ordering::SeqCst) {
+            return true;
+        }
+
+        let exhausted = self.is_exceeded(client);
+
+        if exhausted && !self.exhausted.swap(true, Ordering::SeqCst) {
+            println!("Stopping backup as its budget is exhausted, estimating remaining work...");
+
+            summary.lock().unwrap().remaining = Some((0, 0));
+
+            let _ = self
+                .estimate_deadline
+                .set(Instant::now() + MAX_ESTIMATE_DURATION);
+
+            interrupt();
+        }
+
+        exhausted
+    }
+
+    pub fn is_exceeded(&self, client: &Client) -> bool {
+        self.max_upload
+            .is_some_and(|max_upload| client.uploaded() >= max_upload)
+            || self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.exhausted.load(Ordering::SeqCst)
+    }
+
+    fn estimate_expired(&self) -> bool {
+        self.estimate_deadline
+            .get()
+            .is_some_and(|deadline| Instant::now() >= *deadline)
+    }
+}
+
+pub struct ExcludePatterns {
+    matchers: Vec<Gitignore>,
+}
+
+impl ExcludePatterns {
+    pub fn new(config: &Config, summary: &mut Summary) -> Fallible<Self> {
+        let mut matchers = Vec::new();
+
+        let mut builder = GitignoreBuilder::new("/");
+
+        for pattern in &config.exclude_patterns {
+            builder.add_line(None, pattern)?;
+        }
+
+        matchers.push(builder.build()?);
+
+        for path in &config.exclude_from {
+            let mut builder = GitignoreBuilder::new(path.parent().unwrap_or(Path::new("/")));
+
+            if let Some(err) = builder.add(path) {
+                let err = if err.is_io() {
+                    err.into_io_error().unwrap()
+                } else {
+                    IoError::other(err)
+                };
+
+                match config.on_error {
+                    ErrorPolicy::Abort => return Err(err.into()),
+                    ErrorPolicy::Skip => {
+                        eprintln!(
+                            "Skipping exclude patterns from {} due to error: {}",
+                            path.display(),
+                            err
+                        );
+
+                        summary.errors.push((path.clone(), err));
+                    }
+                }
+            }
+
+            matchers.push(builder.build()?);
+        }
+
+        Ok(Self { matchers })
+    }
+
+    fn matched(&self, path: &Path, is_dir: bool) -> Option<&str> {
+        for matcher in self.matchers.iter().rev() {
+            if !path.starts_with(matcher.path()) {
+                continue;
+            }
+
+            match matcher.matched(path, is_dir) {
+                Match::None => (),
+                Match::Ignore(glob) => return Some(glob.original()),
+                Match::Whitelist(_) => return None,
+            }
+        }
+
+        None
+    }
+}
+
+pub struct Backup<'a, 'b> {
+    pub config: &'a Config,
+    pub client: &'a Client<'a>,
+    pub update: &'a Mutex<Update<'b>>,
+    pub index: &'a BlockIndex,
+    pub summary: &'a Mutex<Summary>,
+    pub exclude_patterns: &'a ExcludePatterns,
+    pub budget: &'a Budget,
+    pub rehash_all: bool,
+    pub hard_links: Mutex<HashMap<(u64, u64), PathBuf>>,
+}
+
+impl Backup<'_, '_> {
+    pub fn backup_include(&self, include: &Include) -> Fallible {
+        let path = include.path();
+
+        let device = if include.one_file_system(self.config) {
+            Some(try_io!(self, path, path.symlink_metadata()).dev())
+        } else {
+            None
+        };
+
+        self.backup(path, include, device)?;
+
+        if include.keep_deleted_files(self.config) && !self.config.keep_deleted_files {
+            self.update.lock().unwrap().retain(path)?;
+        }
+
+        Ok(())
+    }
+
+    fn backup(&self, path: &Path, include: &Include, device: Option<u64>) -> Fallible {
+        if !self.budget.check(self.client, self.summary) && was_interrupted() {
+            return Ok(());
+        }
+
+        if self.budget.estimate_expired() {
+            self.summary.lock().unwrap().estimate_stopped = true;
+            return Ok(());
+        }
+
+        if let Some(exclude) = self
+            .config
+            .excludes
+            .iter()
+            .chain(include.excludes())
+            .find(|exclude| path.starts_with(exclude))
+        {
+            println!(
+                "Skipping {} due to exclude {}",
+                path.display(),
+                exclude.display(),
+            );
+            return Ok(());
+        }
+
+        let metadata = try_io!(self, path, path.symlink_metadata());
+        let file_type = metadata.file_type();
+
+        if let Some(pattern) = self.exclude_patterns.matched(path, file_type.is_dir()) {
+            println!(
+                "Skipping {} due to exclude pattern {}",
+                path.display(),
+                pattern,
+            );
+            return Ok(());
+        }
+
+        if self.budget.is_exhausted() {
+            if file_type.is_dir() {
+                self.backup_dir(path, &metadata, include, device)?;
+            } else if file_type.is_file() {
+                self.estimate_file(path, &metadata)?;
+            }
+
+            return Ok(());
+        }
+
+        if file_type.is_dir() {
+            self.backup_dir(path, &metadata, include, device)?;
+        } else if file_type.is_file() {
+            self.backup_file(path, &metadata, include)?;
+        } else if file_type.is_symlink() {
+            self.backup_symlink(path, &metadata)?;
+        } else if file_type.is_fifo() || file_type.is_char_device() || file_type.is_block_device() {
+            self.update.lock().unwrap().special_file(path, &metadata)?;
+        } else if file_type.is_socket() {
+            eprintln!("Skipping {} as it is a socket", path.display());
+        } else {
+            eprintln!(
+                "Skipping {} as it does not appear to be a regular file",
+                path.display()
+            );
+        }
+
+        Ok(())
+    }
+
+    fn backup_dir(
+        &self,
+        path: &Path,
+        metadata: &Metadata,
+        include: &Include,
+        device: Option<u64>,
+    ) -> Fallible {
+        if device.is_some_and(|device| device != metadata.dev()) {
+            println!(
+                "Skipping contents of {} as it is on another file system",
+                path.display()
+            );
+
+            if self.budget.is_exhausted() {
+                return Ok(());
+            }
+
+            let xattrs = try_io!(self, path, self.xattrs(path));
+
+            self.update
+                .lock()
+                .unwrap()
+                .directory(path, metadata, &xattrs)?;
+
+            return Ok(());
+        }
+
+        let dir = try_io!(self, path, path.read_dir());
+
+        let paths = try_io!(
+            self,
+            path,
+            dir.map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>, _>>()
+        );
 
-    let new_file_id = update.lock().unwrap().open_file(path, metadata)?;
+        let markers = try_io!(self, path, self.markers(&paths));
 
-    let mut offset = 0;
+        if let Some(marker) = markers.first() {
+            println!(
+                "Skipping {} due to marker file {}",
+                path.display(),
+                marker.display()
+            );
+
+            self.summary
+                .lock()
+                .unwrap()
+                .marked_dirs
+                .push(path.to_owned());
+
+            if !self.config.keep_exclude_markers {
+                return Ok(());
+            }
+        }
 
-    split(file, |block| {
-        store_block(update, config, client, new_file_id, offset, block)?;
+        if !self.budget.is_exhausted() {
+            let xattrs = try_io!(self, path, self.xattrs(path));
 
-        offset += u64::try_from(block.len()).unwrap();
+            self.update
+                .lock()
+                .unwrap()
+                .directory(path, metadata, &xattrs)?;
+        }
+
+        let paths = if markers.is_empty() { paths } else { markers };
+
+        paths
+            .par_iter()
+            .try_for_each(|path| self.backup(path, include, device))
+    }
+
+    fn backup_file(&self, path: &Path, metadata: &Metadata, include: &Include) -> Fallible {
+        if !self.rehash_all && self.update.lock().unwrap().unchanged_file(path, metadata)? {
+            return Ok(());
+        }
+
+        let xattrs = try_io!(self, path, self.xattrs(path));
+
+        let new_file_id = if metadata.nlink() > 1 {
+            let mut hard_links = self.hard_links.lock().unwrap();
+
+            match hard_links.entry((metadata.dev(), metadata.ino())) {
+                Entry::Occupied(entry) => {
+                    let target = entry.get().clone();
+                    drop(hard_links);
+
+                    self.update
+                        .lock()
+                        .unwrap()
+                        .hard_link(path, metadata, &xattrs, &target)?;
+
+                    return Ok(());
+                }
+                Entry::Vacant(entry) => {
+                    // Links must not be collected before their target is opened.
+                    let new_file_id = self
+                        .update
+                        .lock()
+                        .unwrap()
+                        .open_file(path, metadata, &xattrs)?;
+
+                    entry.insert(path.to_owned());
+
+                    new_file_id
+                }
+            }
+        } else {
+            self.update
+                .lock()
+                .unwrap()
+                .open_file(path, metadata, &xattrs)?
+        };
+
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) => return self.skip_file(path, new_file_id, err),
+        };
+
+        let extents = match data_extents(&file, metadata.len()) {
+            Ok(extents) => extents,
+            Err(err) => return self.skip_file(path, new_file_id, err),
+        };
+
+        let compression_level = include.compression_level(self.config);
+        let chunk_bits = include.chunk_bits(self.config);
+
+        let mut mappings = Vec::new();
+
+        for (start, end) in extents {
+            let mut reader = &file;
+
+            if let Err(err) = reader.seek(SeekFrom::Start(start)) {
+                return self.skip_file(path, new_file_id, err);
+            }
+
+            let mut reader = TrackedReader {
+                reader: reader.take(end - start),
+                err: None,
+            };
+
+            let mut offset = start;
+
+            let res = split(&mut reader, chunk_bits, |block| {
+                let block_id = store_block(
+                    self.update,
+                    self.index,
+                    self.config,
+                    compression_level,
+                    block,
+                )?;
+
+                mappings.push((offset, block_id));
+
+                offset += u64::try_from(block.len()).unwrap();
+
+                Ok(())
+            });
+
+            if let Some(err) = reader.err {
+                return self.skip_file(path, new_file_id, err);
+            }
+
+            res?;
+        }
+
+        self.update
+            .lock()
+            .unwrap()
+            .close_file(new_file_id, &mappings)?;
 
         Ok(())
-    })?;
+    }
 
-    update.lock().unwrap().close_file(new_file_id)?;
+    fn skip_file(&self, path: &Path, new_file_id: i64, err: IoError) -> Fallible {
+        self.update.lock().unwrap().discard_file(new_file_id)?;
 
-    Ok(())
+        if err.kind() == ErrorKind::NotFound {
+            return Ok(());
+        }
+
+        self.skip(path, err)
+    }
+
+    fn estimate_file(&self, path: &Path, metadata: &Metadata) -> Fallible {
+        if !self.rehash_all && self.update.lock().unwrap().unchanged_file(path, metadata)? {
+            return Ok(());
+        }
+
+        if let Some((files, size)) = &mut self.summary.lock().unwrap().remaining {
+            *files += 1;
+            *size += metadata.len();
+        }
+
+        Ok(())
+    }
+
+    fn backup_symlink(&self, path: &Path, metadata: &Metadata) -> Fallible {
+        let target = try_io!(self, path, path.read_link());
+        let xattrs = try_io!(self, path, self.xattrs(path));
+
+        self.update
+            .lock()
+            .unwrap()
+            .symlink(path, &target, metadata, &xattrs)?;
+
+        Ok(())
+    }
+
+    fn markers(&self, paths: &[PathBuf]) -> IoResult<Vec<PathBuf>> {
+        let mut markers = Vec::new();
+
+        for path in paths {
+            let name = match path.file_name() {
+                Some(name) => name,
+                None => continue,
+            };
+
+            if self
+                .config
+                .exclude_markers
+                .iter()
+                .any(|marker| name == marker.as_str())
+                || (self.config.exclude_caches && name == "CACHEDIR.TAG" && is_cache_dir_tag(path)?)
+            {
+                markers.push(path.clone());
+            }
+        }
+
+        Ok(markers)
+    }
+
+    fn xattrs(&self, path: &Path) -> IoResult<Xattrs> {
+        if !self.config.xattrs {
+            return Ok(Xattrs::new());
+        }
+
+        let mut xattrs = read_xattrs(path)?;
+
+        xattrs.retain(|(name, _value)| {
+            !self
+                .config
+                .xattr_excludes
+                .iter()
+                .any(|exclude| name.starts_with(exclude.as_bytes()))
+        });
+
+        Ok(xattrs)
+    }
+
+    fn skip(&self, path: &Path, err: IoError) -> Fallible {
+        match self.config.on_error {
+            ErrorPolicy::Abort => Err(err.into()),
+            ErrorPolicy::Skip => {
+                eprintln!("Skipping {} due to error: {}", path.display(), err);
+
+                self.update.lock().unwrap().retain(path)?;
+
+                self.summary
+                    .lock()
+                    .unwrap()
+                    .errors
+                    .push((path.to_owned(), err));
+
+                Ok(())
+            }
+        }
+    }
 }
 
-fn backup_symlink(update: &Mutex<Update<'_>>, path: &Path) -> Fallible {
-    let target = try_not_found!(path.read_link());
+struct TrackedReader<R> {
+    reader: R,
+    err: Option<IoError>,
+}
+
+impl<R: Read> Read for TrackedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        self.reader.read(buf).map_err(|err| {
+            let kind = err.kind();
+            self.err = Some(err);
+            kind.into()
+        })
+    }
+}
+
+fn is_cache_dir_tag(path: &Path) -> IoResult<bool> {
+    const SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
 
-    update.lock().unwrap().symlink(path, &target)?;
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err),
+    };
 
-    Ok(())
+    let mut buf = Vec::with_capacity(SIGNATURE.len());
+    file.take(u64::try_from(SIGNATURE.len()).unwrap())
+        .read_to_end(&mut buf)?;
+
+    Ok(buf == SIGNATURE)
+}
+
+const MAX_ESTIMATE_DURATION: Duration = Duration::from_secs(60);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_yaml::from_str;
+
+    #[test]
+    fn parses_includes() {
+        let includes = from_str::<Vec<Include>>(
+            r#"
+- /home/bar
+- path: /var/lib/vms
+  excludes: [/var/lib/vms/scratch]
+  one_file_system: true
+  chunk_bits: 20
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(includes[0].path(), Path::new("/home/bar"));
+        assert_eq!(includes[1].path(), Path::new("/var/lib/vms"));
+        assert_eq!(
+            includes[1].excludes(),
+            [PathBuf::from("/var/lib/vms/scratch")]
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_include_options() {
+        let err = from_str::<Vec<Include>>(
+            r#"
+- path: /
+  one_filesystem: true
+"#,
+        )
+        .unwrap_err();
+
+        assert!(err.to_string().contains("unknown field `one_filesystem`"));
+    }
 }
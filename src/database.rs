@@ -16,16 +16,19 @@ GNU General Public License for more details.
 You should have received a copy of the GNU General Public License
 along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
 */
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::fs::Metadata;
 use std::os::unix::{ffi::OsStrExt, fs::MetadataExt};
 use std::path::Path;
 
 use blake3::OUT_LEN as DIGEST_LEN;
+use nix::unistd::{Gid, Group, Uid, User};
 use rusqlite::{
     params,
     types::{FromSqlError, ValueRef},
-    Connection, OptionalExtension,
+    Connection, OpenFlags, OptionalExtension, Row,
 };
 
 use super::Fallible;
@@ -35,6 +38,8 @@ pub fn open_connection(path: &Path) -> Fallible<Connection> {
 
     conn.execute_batch(
         r#"
+PRAGMA journal_mode = WAL;
+
 BEGIN;
 
 CREATE TABLE IF NOT EXISTS patchsets (
@@ -101,11 +106,25 @@ CREATE TEMPORARY TABLE visited_symbolic_links (
     symbolic_link_id INTEGER PIMARY KEY
 );
 
+CREATE TEMPORARY TABLE visited_special_files (
+    special_file_id INTEGER PIMARY KEY
+);
+
 CREATE TEMPORARY TABLE new_files (
     id INTEGER PRIMARY KEY,
     path BLOB NOT NULL UNIQUE,
     size INTEGER NOT NULL,
     mode INTEGER NOT NULL,
+    mtime INTEGER NOT NULL,
+    ctime INTEGER NOT NULL,
+    inode INTEGER NOT NULL,
+    device INTEGER NOT NULL,
+    atime INTEGER NOT NULL,
+    uid INTEGER NOT NULL,
+    gid INTEGER NOT NULL,
+    user_name TEXT,
+    group_name TEXT,
+    hard_link BLOB,
     closed INTEGER NOT NULL DEFAULT FALSE
 );
 
@@ -117,25 +136,274 @@ CREATE TEMPORARY TABLE new_mappings (
 )
 WITHOUT ROWID;
 
+CREATE TEMPORARY TABLE new_file_xattrs (
+    new_file_id INTEGER NOT NULL REFERENCES new_files (id) ON DELETE CASCADE,
+    xattr_id INTEGER NOT NULL,
+    PRIMARY KEY (new_file_id, xattr_id)
+)
+WITHOUT ROWID;
+
 COMMIT;
 "#,
     )?;
 
+    migrate(&conn)?;
+
     conn.set_prepared_statement_cache_capacity(32);
 
     Ok(conn)
 }
 
+pub fn open_read_only_connection(path: &Path) -> Fallible<Connection> {
+    let conn = Connection::open_with_flags(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    if version != MIGRATIONS.len() {
+        return Err(format!(
+            "Manifest {} needs to be migrated by a backup first",
+            path.display()
+        )
+        .into());
+    }
+
+    conn.set_prepared_statement_cache_capacity(32);
+
+    Ok(conn)
+}
+
+const MIGRATIONS: &[&str] = &[
+    r#"
+ALTER TABLE files ADD COLUMN mtime INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE files ADD COLUMN ctime INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE files ADD COLUMN inode INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE files ADD COLUMN device INTEGER NOT NULL DEFAULT 0;
+"#,
+    r#"
+CREATE TABLE snapshots (
+    id INTEGER PRIMARY KEY,
+    timestamp INTEGER NOT NULL,
+    tag TEXT
+);
+
+CREATE TABLE rebuilt_files (
+    id INTEGER PRIMARY KEY,
+    path BLOB NOT NULL,
+    size INTEGER NOT NULL,
+    mode INTEGER NOT NULL,
+    mtime INTEGER NOT NULL DEFAULT 0,
+    ctime INTEGER NOT NULL DEFAULT 0,
+    inode INTEGER NOT NULL DEFAULT 0,
+    device INTEGER NOT NULL DEFAULT 0,
+    created INTEGER NOT NULL DEFAULT 0,
+    deleted INTEGER
+);
+
+INSERT INTO rebuilt_files (id, path, size, mode, mtime, ctime, inode, device)
+SELECT id, path, size, mode, mtime, ctime, inode, device FROM files;
+
+DROP TABLE files;
+ALTER TABLE rebuilt_files RENAME TO files;
+CREATE INDEX files_by_path ON files (path);
+
+CREATE TABLE rebuilt_directories (
+    id INTEGER PRIMARY KEY,
+    path BLOB NOT NULL,
+    mode INTEGER NOT NULL,
+    created INTEGER NOT NULL DEFAULT 0,
+    deleted INTEGER
+);
+
+INSERT INTO rebuilt_directories (id, path, mode)
+SELECT id, path, mode FROM directories;
+
+DROP TABLE directories;
+ALTER TABLE rebuilt_directories RENAME TO directories;
+CREATE INDEX directories_by_path ON directories (path);
+
+CREATE TABLE rebuilt_symbolic_links (
+    id INTEGER PRIMARY KEY,
+    path BLOB NOT NULL,
+    target BLOB NOT NULL,
+    created INTEGER NOT NULL DEFAULT 0,
+    deleted INTEGER
+);
+
+INSERT INTO rebuilt_symbolic_links (id, path, target)
+SELECT id, path, target FROM symbolic_links;
+
+DROP TABLE symbolic_links;
+ALTER TABLE rebuilt_symbolic_links RENAME TO symbolic_links;
+CREATE INDEX symbolic_links_by_path ON symbolic_links (path);
+"#,
+    r#"
+ALTER TABLE files ADD COLUMN atime INTEGER;
+ALTER TABLE files ADD COLUMN uid INTEGER;
+ALTER TABLE files ADD COLUMN gid INTEGER;
+ALTER TABLE files ADD COLUMN user_name TEXT;
+ALTER TABLE files ADD COLUMN group_name TEXT;
+
+ALTER TABLE directories ADD COLUMN atime INTEGER;
+ALTER TABLE directories ADD COLUMN mtime INTEGER;
+ALTER TABLE directories ADD COLUMN uid INTEGER;
+ALTER TABLE directories ADD COLUMN gid INTEGER;
+ALTER TABLE directories ADD COLUMN user_name TEXT;
+ALTER TABLE directories ADD COLUMN group_name TEXT;
+
+ALTER TABLE symbolic_links ADD COLUMN atime INTEGER;
+ALTER TABLE symbolic_links ADD COLUMN mtime INTEGER;
+ALTER TABLE symbolic_links ADD COLUMN uid INTEGER;
+ALTER TABLE symbolic_links ADD COLUMN gid INTEGER;
+ALTER TABLE symbolic_links ADD COLUMN user_name TEXT;
+ALTER TABLE symbolic_links ADD COLUMN group_name TEXT;
+"#,
+    r#"
+ALTER TABLE directories ADD COLUMN ctime INTEGER;
+ALTER TABLE symbolic_links ADD COLUMN ctime INTEGER;
+
+CREATE TABLE xattrs (
+    id INTEGER PRIMARY KEY,
+    name BLOB NOT NULL,
+    value BLOB NOT NULL,
+    UNIQUE (name, value)
+);
+
+CREATE TABLE file_xattrs (
+    file_id INTEGER NOT NULL REFERENCES files (id) ON DELETE CASCADE,
+    xattr_id INTEGER NOT NULL REFERENCES xattrs (id),
+    PRIMARY KEY (file_id, xattr_id)
+)
+WITHOUT ROWID;
+
+CREATE INDEX file_xattrs_by_xattr ON file_xattrs (xattr_id);
+
+CREATE TABLE directory_xattrs (
+    directory_id INTEGER NOT NULL REFERENCES directories (id) ON DELETE CASCADE,
+    xattr_id INTEGER NOT NULL REFERENCES xattrs (id),
+    PRIMARY KEY (directory_id, xattr_id)
+)
+WITHOUT ROWID;
+
+CREATE INDEX directory_xattrs_by_xattr ON directory_xattrs (xattr_id);
+
+CREATE TABLE symbolic_link_xattrs (
+    symbolic_link_id INTEGER NOT NULL REFERENCES symbolic_links (id) ON DELETE CASCADE,
+    xattr_id INTEGER NOT NULL REFERENCES xattrs (id),
+    PRIMARY KEY (symbolic_link_id, xattr_id)
+)
+WITHOUT ROWID;
+
+CREATE INDEX symbolic_link_xattrs_by_xattr ON symbolic_link_xattrs (xattr_id);
+"#,
+    r#"
+ALTER TABLE files ADD COLUMN hard_link BLOB;
+
+CREATE INDEX files_by_inode ON files (device, inode);
+"#,
+    r#"
+CREATE TABLE special_files (
+    id INTEGER PRIMARY KEY,
+    path BLOB NOT NULL,
+    mode INTEGER NOT NULL,
+    rdev INTEGER NOT NULL,
+    created INTEGER NOT NULL,
+    deleted INTEGER,
+    atime INTEGER,
+    mtime INTEGER,
+    ctime INTEGER,
+    uid INTEGER,
+    gid INTEGER,
+    user_name TEXT,
+    group_name TEXT
+);
+
+CREATE INDEX special_files_by_path ON special_files (path);
+"#,
+    r#"
+ALTER TABLE archives ADD COLUMN pool_name TEXT;
+
+CREATE UNIQUE INDEX archives_by_pool_name ON archives (pool_name);
+
+CREATE TABLE pool_archives (
+    name TEXT PRIMARY KEY,
+    length INTEGER NOT NULL,
+    b2_file_id TEXT NOT NULL,
+    b2_length INTEGER NOT NULL
+);
+
+CREATE TABLE pool_blocks (
+    digest BLOB NOT NULL,
+    archive_name TEXT NOT NULL REFERENCES pool_archives (name) ON DELETE CASCADE,
+    archive_off INTEGER NOT NULL
+);
+
+CREATE INDEX pool_blocks_by_digest ON pool_blocks (digest);
+
+CREATE INDEX pool_blocks_by_archive ON pool_blocks (archive_name);
+"#,
+    r#"
+ALTER TABLE patchsets ADD COLUMN data BLOB;
+"#,
+];
+
+#[derive(Clone)]
+pub struct Attributes {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub user_name: Option<String>,
+    pub group_name: Option<String>,
+    pub times: Option<(i64, i64)>,
+}
+
+impl Attributes {
+    fn from_row(row: &Row, idx: usize) -> Fallible<Self> {
+        let atime: Option<i64> = row.get(idx + 4)?;
+        let mtime: Option<i64> = row.get(idx + 5)?;
+
+        Ok(Self {
+            uid: row.get(idx)?,
+            gid: row.get(idx + 1)?,
+            user_name: row.get(idx + 2)?,
+            group_name: row.get(idx + 3)?,
+            times: atime.zip(mtime),
+        })
+    }
+}
+
+fn migrate(conn: &Connection) -> Fallible {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    for (idx, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        conn.execute_batch(&format!(
+            "PRAGMA foreign_keys = OFF; BEGIN; {migration} PRAGMA user_version = {}; COMMIT; PRAGMA foreign_keys = ON;",
+            idx + 1
+        ))?;
+    }
+
+    Ok(())
+}
+
 pub fn clear_tables(conn: &Connection) -> Fallible {
     conn.execute_batch(
         r#"
 DELETE FROM mappings;
 DELETE FROM blocks;
+DELETE FROM symbolic_link_xattrs;
+DELETE FROM directory_xattrs;
+DELETE FROM file_xattrs;
+DELETE FROM xattrs;
+DELETE FROM special_files;
 DELETE FROM symbolic_links;
 DELETE FROM directories;
 DELETE FROM files;
 DELETE FROM archives;
+DELETE FROM snapshots;
 DELETE FROM patchsets;
+DELETE FROM pool_blocks;
+DELETE FROM pool_archives;
         "#,
     )?;
 
@@ -159,6 +427,25 @@ pub fn insert_def_patchset(conn: &Connection) -> Fallible<i64> {
     Ok(patchset_id)
 }
 
+pub fn insert_pending_patchset(conn: &Connection, patchset: &[u8]) -> Fallible<i64> {
+    conn.execute("INSERT INTO patchsets (data) VALUES (?)", params![patchset])?;
+    let patchset_id = conn.last_insert_rowid();
+
+    Ok(patchset_id)
+}
+
+pub fn select_pending_patchsets(conn: &Connection) -> Fallible<Vec<(i64, Vec<u8>)>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, data FROM patchsets WHERE b2_file_id IS NULL AND data IS NOT NULL ORDER BY id",
+    )?;
+
+    let rows = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(rows)
+}
+
 pub fn insert_patchset(
     conn: &Connection,
     patchset_id: i64,
@@ -180,19 +467,128 @@ pub fn update_patchset(
     b2_length: u64,
 ) -> Fallible {
     conn.execute(
-        "UPDATE patchsets SET b2_file_id = ?, b2_length = ? WHERE id = ?",
+        "UPDATE patchsets SET b2_file_id = ?, b2_length = ?, data = NULL WHERE id = ?",
         params![b2_file_id, b2_length as i64, patchset_id],
     )?;
 
     Ok(())
 }
 
+pub fn select_patchset_lengths(conn: &Connection) -> Fallible<Vec<(i64, u64)>> {
+    let mut stmt =
+        conn.prepare("SELECT id, b2_length FROM patchsets WHERE b2_file_id IS NOT NULL")?;
+
+    let rows = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64)))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(rows)
+}
+
 pub fn delete_patchset(conn: &Connection, patchset_id: i64) -> Fallible {
     conn.execute("DELETE FROM patchsets WHERE id = ?", params![patchset_id])?;
 
     Ok(())
 }
 
+pub fn select_next_snapshot(conn: &Connection) -> Fallible<i64> {
+    let snapshot_id =
+        conn.query_row("SELECT IFNULL(MAX(id), 0) + 1 FROM snapshots", [], |row| {
+            row.get(0)
+        })?;
+
+    Ok(snapshot_id)
+}
+
+pub fn select_latest_snapshot(conn: &Connection) -> Fallible<Option<i64>> {
+    let snapshot_id = conn.query_row("SELECT MAX(id) FROM snapshots", [], |row| row.get(0))?;
+
+    Ok(snapshot_id)
+}
+
+pub fn select_snapshot(conn: &Connection, snapshot_id: i64) -> Fallible<bool> {
+    let mut stmt = conn.prepare_cached("SELECT TRUE FROM snapshots WHERE id = ?")?;
+
+    let exists: Option<bool> = stmt
+        .query_row(params![snapshot_id], |row| row.get(0))
+        .optional()?;
+
+    Ok(exists.is_some())
+}
+
+pub fn select_snapshot_by_time(conn: &Connection, time: &str) -> Fallible<Option<i64>> {
+    let timestamp: Option<i64> =
+        conn.query_row("SELECT unixepoch(?, 'utc')", params![time], |row| {
+            row.get(0)
+        })?;
+
+    let timestamp = timestamp.ok_or_else(|| format!("Invalid snapshot time {time}"))?;
+
+    let snapshot_id = conn
+        .query_row(
+            "SELECT id FROM snapshots WHERE timestamp <= ? ORDER BY id DESC LIMIT 1",
+            params![timestamp],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    Ok(snapshot_id)
+}
+
+pub fn select_snapshots(
+    conn: &Connection,
+    mut consumer: impl FnMut(i64, &str, Option<&str>) -> Fallible,
+) -> Fallible {
+    let mut stmt = conn.prepare(
+        "SELECT id, datetime(timestamp, 'unixepoch', 'localtime'), tag FROM snapshots ORDER BY id ASC",
+    )?;
+
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let snapshot_id = row.get(0)?;
+        let time = row.get_ref_unwrap(1).as_str()?;
+        let tag = row.get_ref_unwrap(2).as_str_or_null()?;
+
+        consumer(snapshot_id, time, tag)?;
+    }
+
+    Ok(())
+}
+
+pub fn select_snapshot_changes(conn: &Connection, snapshot_id: i64) -> Fallible<bool> {
+    let changes = conn.query_row(
+        r#"
+SELECT
+    EXISTS (SELECT id FROM files WHERE created = ?1 OR deleted = ?1)
+    OR EXISTS (SELECT id FROM directories WHERE created = ?1 OR deleted = ?1)
+    OR EXISTS (SELECT id FROM symbolic_links WHERE created = ?1 OR deleted = ?1)
+    OR EXISTS (SELECT id FROM special_files WHERE created = ?1 OR deleted = ?1)
+"#,
+        params![snapshot_id],
+        |row| row.get(0),
+    )?;
+
+    Ok(changes)
+}
+
+pub fn insert_snapshot(conn: &Connection, snapshot_id: i64, tag: Option<&str>) -> Fallible {
+    conn.execute(
+        "INSERT INTO snapshots (id, timestamp, tag) VALUES (?, unixepoch(), ?)",
+        params![snapshot_id, tag],
+    )?;
+
+    Ok(())
+}
+
+pub fn delete_expired_snapshots(conn: &Connection, keep_snapshots: usize) -> Fallible<usize> {
+    let rows = conn.execute(
+        "DELETE FROM snapshots WHERE id NOT IN (SELECT id FROM snapshots ORDER BY id DESC LIMIT ?)",
+        params![keep_snapshots as i64],
+    )?;
+
+    Ok(rows)
+}
+
 pub fn select_archive(conn: &Connection, archive_id: i64) -> Fallible<bool> {
     let mut stmt = conn.prepare_cached("SELECT TRUE FROM archives WHERE id = ?")?;
 
@@ -203,8 +599,11 @@ pub fn select_archive(conn: &Connection, archive_id: i64) -> Fallible<bool> {
     Ok(exists.is_some())
 }
 
-pub fn insert_def_archive(conn: &Connection) -> Fallible<i64> {
-    conn.execute("INSERT INTO archives DEFAULT VALUES", [])?;
+pub fn insert_def_archive(conn: &Connection, pool_name: Option<&str>) -> Fallible<i64> {
+    conn.execute(
+        "INSERT INTO archives (pool_name) VALUES (?)",
+        params![pool_name],
+    )?;
     let archive_id = conn.last_insert_rowid();
 
     Ok(archive_id)
@@ -216,15 +615,183 @@ pub fn update_archive(
     length: u64,
     b2_file_id: &str,
     b2_length: u64,
+    pool_name: Option<&str>,
+) -> Fallible {
+    conn.execute(
+        "UPDATE archives SET length = ?, b2_file_id = ?, b2_length = ?, pool_name = ? WHERE id = ?",
+        params![
+            length as i64,
+            b2_file_id,
+            b2_length as i64,
+            pool_name,
+            archive_id
+        ],
+    )?;
+
+    Ok(())
+}
+
+pub fn select_archive_pool_name(conn: &Connection, archive_id: i64) -> Fallible<Option<String>> {
+    let mut stmt = conn.prepare_cached("SELECT pool_name FROM archives WHERE id = ?")?;
+
+    let pool_name = stmt.query_row(params![archive_id], |row| row.get(0))?;
+
+    Ok(pool_name)
+}
+
+pub fn select_archive_lengths(conn: &Connection) -> Fallible<Vec<(i64, u64)>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, b2_length FROM archives WHERE b2_file_id IS NOT NULL AND pool_name IS NULL",
+    )?;
+
+    let rows = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64)))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(rows)
+}
+
+pub fn select_pool_archive_lengths(conn: &Connection) -> Fallible<Vec<(i64, String, u64)>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, pool_name, b2_length FROM archives WHERE b2_file_id IS NOT NULL AND pool_name IS NOT NULL",
+    )?;
+
+    let rows = stmt
+        .query_map([], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get::<_, i64>(2)? as u64))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(rows)
+}
+
+pub fn select_referenced_pool_archives(conn: &Connection) -> Fallible<Vec<String>> {
+    let mut stmt = conn
+        .prepare("SELECT pool_name FROM archives WHERE pool_name IS NOT NULL ORDER BY pool_name")?;
+
+    let rows = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(rows)
+}
+
+pub fn select_pool_archive(conn: &Connection, name: &str) -> Fallible<Option<i64>> {
+    let mut stmt = conn.prepare_cached("SELECT id FROM archives WHERE pool_name = ?")?;
+
+    let archive_id = stmt.query_row(params![name], |row| row.get(0)).optional()?;
+
+    Ok(archive_id)
+}
+
+pub fn insert_pool_archive_reference(conn: &Connection, name: &str) -> Fallible<i64> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+INSERT INTO archives (length, b2_file_id, b2_length, pool_name)
+SELECT length, b2_file_id, b2_length, name
+FROM pool_archives
+WHERE name = ?
+"#,
+    )?;
+
+    stmt.execute(params![name])?;
+    let archive_id = conn.last_insert_rowid();
+
+    Ok(archive_id)
+}
+
+pub fn select_pool_archive_names(conn: &Connection) -> Fallible<Vec<String>> {
+    let mut stmt = conn.prepare("SELECT name FROM pool_archives")?;
+
+    let rows = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(rows)
+}
+
+pub fn insert_pool_archive(
+    conn: &Connection,
+    name: &str,
+    length: u64,
+    b2_file_id: &str,
+    b2_length: u64,
 ) -> Fallible {
     conn.execute(
-        "UPDATE archives SET length = ?, b2_file_id = ?, b2_length = ? WHERE id = ?",
-        params![length as i64, b2_file_id, b2_length as i64, archive_id],
+        "INSERT OR REPLACE INTO pool_archives (name, length, b2_file_id, b2_length) VALUES (?, ?, ?, ?)",
+        params![name, length as i64, b2_file_id, b2_length as i64],
     )?;
 
     Ok(())
 }
 
+pub fn delete_pool_archive(conn: &Connection, name: &str) -> Fallible {
+    conn.execute("DELETE FROM pool_archives WHERE name = ?", params![name])?;
+
+    Ok(())
+}
+
+pub fn insert_pool_block(
+    conn: &Connection,
+    digest: &[u8],
+    archive_name: &str,
+    archive_off: u64,
+) -> Fallible {
+    let mut stmt = conn.prepare_cached(
+        "INSERT INTO pool_blocks (digest, archive_name, archive_off) VALUES (?, ?, ?)",
+    )?;
+
+    stmt.execute(params![digest, archive_name, archive_off as i64])?;
+
+    Ok(())
+}
+
+pub fn select_pool_block(conn: &Connection, digest: &[u8]) -> Fallible<Option<(String, u64)>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT archive_name, archive_off FROM pool_blocks WHERE digest = ? LIMIT 1",
+    )?;
+
+    let block = stmt
+        .query_row(params![digest], |row| {
+            Ok((row.get(0)?, row.get::<_, i64>(1)? as u64))
+        })
+        .optional()?;
+
+    Ok(block)
+}
+
+pub fn select_sampled_archives(conn: &Connection, percent: u8) -> Fallible<Vec<i64>> {
+    let mut stmt = conn.prepare(
+        r#"
+SELECT id
+FROM archives
+WHERE b2_file_id IS NOT NULL
+ORDER BY RANDOM()
+LIMIT (
+    SELECT (COUNT(*) * ? + 99) / 100
+    FROM archives
+    WHERE b2_file_id IS NOT NULL
+)
+"#,
+    )?;
+
+    let rows = stmt
+        .query_map(params![percent], |row| row.get(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(rows)
+}
+
+pub fn select_pending_archives(conn: &Connection) -> Fallible<Vec<(i64, Option<String>)>> {
+    let mut stmt = conn.prepare("SELECT id, pool_name FROM archives WHERE b2_file_id IS NULL")?;
+
+    let rows = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(rows)
+}
+
 pub fn delete_archive(conn: &Connection, archive_id: i64) -> Fallible {
     conn.execute("DELETE FROM archives WHERE id = ?", params![archive_id])?;
 
@@ -234,6 +801,7 @@ pub fn delete_archive(conn: &Connection, archive_id: i64) -> Fallible {
 pub fn select_archives_by_path(
     conn: &Connection,
     path_filter: Option<&Path>,
+    snapshot_id: Option<i64>,
     mut consumer: impl FnMut(i64) -> Fallible,
 ) -> Fallible {
     let mut stmt = conn.prepare(
@@ -242,11 +810,12 @@ SELECT DISTINCT blocks.archive_id
 FROM files, mappings, blocks
 WHERE files.id = mappings.file_id
 AND mappings.block_id = blocks.id
-AND IFNULL(files.path GLOB ?, TRUE)
+AND IFNULL(files.path GLOB ?1, TRUE)
+AND IFNULL(files.created <= ?2 AND IFNULL(files.deleted > ?2, TRUE), files.deleted IS NULL)
 "#,
     )?;
 
-    let mut rows = stmt.query(params![path_filter.map(path_as_bytes)])?;
+    let mut rows = stmt.query(params![path_filter.map(path_as_bytes), snapshot_id])?;
     while let Some(row) = rows.next()? {
         let archive_id = row.get(0)?;
 
@@ -256,222 +825,656 @@ AND IFNULL(files.path GLOB ?, TRUE)
     Ok(())
 }
 
-pub fn select_file(conn: &Connection, path: &Path) -> Fallible<Option<i64>> {
-    let mut stmt = conn.prepare_cached("SELECT id FROM files WHERE path = ?")?;
+pub fn select_unchanged_file(
+    conn: &Connection,
+    path: &Path,
+    metadata: &Metadata,
+) -> Fallible<Option<i64>> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+SELECT id
+FROM files
+WHERE path = ?
+AND size = ?
+AND mtime = ?
+AND ctime = ?
+AND inode = ?
+AND device = ?
+AND deleted IS NULL
+"#,
+    )?;
 
     let file_id = stmt
-        .query_row(params![path_as_bytes(path)], |row| row.get(0))
+        .query_row(
+            params![
+                path_as_bytes(path),
+                metadata.size() as i64,
+                mtime(metadata),
+                ctime(metadata),
+                metadata.ino() as i64,
+                metadata.dev() as i64,
+            ],
+            |row| row.get(0),
+        )
         .optional()?;
 
     Ok(file_id)
 }
 
-pub fn insert_file(conn: &Connection, new_file_id: i64) -> Fallible<i64> {
+pub fn insert_file(conn: &Connection, new_file_id: i64, created: i64) -> Fallible<i64> {
     let mut stmt = conn.prepare_cached(
-        "INSERT INTO files (path, size, mode) SELECT path, size, mode FROM new_files WHERE id = ?",
+        r#"
+INSERT INTO files (path, size, mode, mtime, ctime, inode, device, created, atime, uid, gid, user_name, group_name, hard_link)
+SELECT path, size, mode, mtime, ctime, inode, device, ?, atime, uid, gid, user_name, group_name, hard_link
+FROM new_files
+WHERE id = ?
+"#,
     )?;
 
-    stmt.execute(params![new_file_id])?;
+    stmt.execute(params![created, new_file_id])?;
     let file_id = conn.last_insert_rowid();
 
     Ok(file_id)
 }
 
-pub fn update_file(conn: &Connection, file_id: i64, new_file_id: i64) -> Fallible {
-    let mut stmt = conn.prepare_cached("SELECT size, mode FROM new_files WHERE id = ?")?;
+pub fn update_file(conn: &Connection, path: &Path, deleted: i64) -> Fallible {
+    let mut stmt =
+        conn.prepare_cached("UPDATE files SET deleted = ? WHERE path = ? AND deleted IS NULL")?;
 
-    let mut rows = stmt.query(params![new_file_id])?;
-    let row = rows
-        .next()?
-        .ok_or_else(|| format!("Invalid new file ID {new_file_id}"))?;
+    stmt.execute(params![deleted, path_as_bytes(path)])?;
 
-    let size = row.get_ref_unwrap(0).as_i64()?;
-    let mode = row.get_ref_unwrap(1).as_i64()?;
+    Ok(())
+}
 
-    let mut stmt = conn.prepare_cached("UPDATE files SET size = ?, mode = ? WHERE id = ?")?;
+pub fn select_files_by_path(
+    conn: &Connection,
+    path_filter: Option<&Path>,
+    snapshot_id: Option<i64>,
+    mut consumer: impl FnMut(i64, &Path, u64, u32, &Attributes, Option<&Path>) -> Fallible,
+) -> Fallible {
+    let mut stmt = conn.prepare(
+        r#"
+SELECT
+    id,
+    path,
+    size,
+    mode,
+    uid,
+    gid,
+    user_name,
+    group_name,
+    atime,
+    mtime,
+    (
+        SELECT MIN(links.path)
+        FROM files links
+        WHERE links.device = files.device
+        AND links.inode = files.inode
+        AND links.ctime = files.ctime
+        AND IFNULL(links.path GLOB ?1, TRUE)
+        AND IFNULL(links.created <= ?2 AND IFNULL(links.deleted > ?2, TRUE), links.deleted IS NULL)
+        HAVING MAX(links.hard_link IS NOT NULL) AND MIN(links.path) < files.path
+    )
+FROM files
+WHERE IFNULL(path GLOB ?1, TRUE)
+AND IFNULL(created <= ?2 AND IFNULL(deleted > ?2, TRUE), deleted IS NULL)
+"#,
+    )?;
+
+    let mut rows = stmt.query(params![path_filter.map(path_as_bytes), snapshot_id])?;
+    while let Some(row) = rows.next()? {
+        let file_id = row.get(0)?;
+        let path = path_from_blob(row.get_ref_unwrap(1))?;
+        let size = row.get_ref_unwrap(2).as_i64()? as u64;
+        let mode = row.get(3)?;
+        let attrs = Attributes::from_row(row, 4)?;
+        let hard_link = row
+            .get_ref_unwrap(10)
+            .as_blob_or_null()?
+            .map(|blob| Path::new(OsStr::from_bytes(blob)));
+
+        consumer(file_id, path, size, mode, &attrs, hard_link)?;
+    }
+
+    Ok(())
+}
+
+pub fn select_files_by_path_and_archive(
+    conn: &Connection,
+    path_filter: Option<&Path>,
+    snapshot_id: Option<i64>,
+    archive_id: i64,
+    mut consumer: impl FnMut(i64, &Path) -> Fallible,
+) -> Fallible {
+    let mut stmt = conn.prepare(
+        r#"
+SELECT
+    id,
+    path
+FROM files
+WHERE IFNULL(path GLOB ?1, TRUE)
+AND IFNULL(created <= ?2 AND IFNULL(deleted > ?2, TRUE), deleted IS NULL)
+AND id IN (
+    SELECT mappings.file_id
+    FROM mappings, blocks
+    WHERE mappings.block_id = blocks.id
+    AND blocks.archive_id = ?3
+)
+AND NOT EXISTS (
+    SELECT MIN(links.path)
+    FROM files links
+    WHERE links.device = files.device
+    AND links.inode = files.inode
+    AND links.ctime = files.ctime
+    AND IFNULL(links.path GLOB ?1, TRUE)
+    AND IFNULL(links.created <= ?2 AND IFNULL(links.deleted > ?2, TRUE), links.deleted IS NULL)
+    HAVING MAX(links.hard_link IS NOT NULL) AND MIN(links.path) < files.path
+)
+"#,
+    )?;
+
+    let mut rows = stmt.query(params![
+        path_filter.map(path_as_bytes),
+        snapshot_id,
+        archive_id
+    ])?;
+    while let Some(row) = rows.next()? {
+        let file_id = row.get(0)?;
+        let path = path_from_blob(row.get_ref_unwrap(1))?;
+
+        consumer(file_id, path)?;
+    }
+
+    Ok(())
+}
+
+pub fn select_unchanged_directory(
+    conn: &Connection,
+    path: &Path,
+    metadata: &Metadata,
+) -> Fallible<Option<i64>> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+SELECT id
+FROM directories
+WHERE path = ?
+AND mode = ?
+AND mtime IS ?
+AND ctime IS ?
+AND uid IS ?
+AND gid IS ?
+AND deleted IS NULL
+"#,
+    )?;
+
+    let directory_id = stmt
+        .query_row(
+            params![
+                path_as_bytes(path),
+                metadata.mode(),
+                mtime(metadata),
+                ctime(metadata),
+                metadata.uid(),
+                metadata.gid(),
+            ],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    Ok(directory_id)
+}
+
+pub fn insert_directory(
+    conn: &Connection,
+    path: &Path,
+    metadata: &Metadata,
+    created: i64,
+) -> Fallible<i64> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+INSERT INTO directories (path, mode, created, atime, mtime, uid, gid, user_name, group_name, ctime)
+VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+"#,
+    )?;
+
+    stmt.execute(params![
+        path_as_bytes(path),
+        metadata.mode(),
+        created,
+        atime(metadata),
+        mtime(metadata),
+        metadata.uid(),
+        metadata.gid(),
+        user_name(metadata.uid()),
+        group_name(metadata.gid()),
+        ctime(metadata),
+    ])?;
+    let directory_id = conn.last_insert_rowid();
+
+    Ok(directory_id)
+}
+
+pub fn update_directory(conn: &Connection, path: &Path, deleted: i64) -> Fallible {
+    let mut stmt = conn
+        .prepare_cached("UPDATE directories SET deleted = ? WHERE path = ? AND deleted IS NULL")?;
+
+    stmt.execute(params![deleted, path_as_bytes(path)])?;
+
+    Ok(())
+}
+
+pub fn select_directories_by_path(
+    conn: &Connection,
+    path_filter: Option<&Path>,
+    snapshot_id: Option<i64>,
+    mut consumer: impl FnMut(&Path, u32, &Attributes) -> Fallible,
+) -> Fallible {
+    let mut stmt = conn.prepare(
+        r#"
+SELECT path, mode, uid, gid, user_name, group_name, atime, mtime
+FROM directories
+WHERE IFNULL(path GLOB ?1, TRUE)
+AND IFNULL(created <= ?2 AND IFNULL(deleted > ?2, TRUE), deleted IS NULL)
+"#,
+    )?;
+
+    let mut rows = stmt.query(params![path_filter.map(path_as_bytes), snapshot_id])?;
+    while let Some(row) = rows.next()? {
+        let path = path_from_blob(row.get_ref_unwrap(0))?;
+        let mode = row.get(1)?;
+        let attrs = Attributes::from_row(row, 2)?;
+
+        consumer(path, mode, &attrs)?;
+    }
+
+    Ok(())
+}
+
+pub fn select_unchanged_symbolic_link(
+    conn: &Connection,
+    path: &Path,
+    target: &Path,
+    metadata: &Metadata,
+) -> Fallible<Option<i64>> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+SELECT id
+FROM symbolic_links
+WHERE path = ?
+AND target = ?
+AND mtime IS ?
+AND ctime IS ?
+AND uid IS ?
+AND gid IS ?
+AND deleted IS NULL
+"#,
+    )?;
+
+    let symbolic_link_id = stmt
+        .query_row(
+            params![
+                path_as_bytes(path),
+                path_as_bytes(target),
+                mtime(metadata),
+                ctime(metadata),
+                metadata.uid(),
+                metadata.gid(),
+            ],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    Ok(symbolic_link_id)
+}
+
+pub fn insert_symbolic_link(
+    conn: &Connection,
+    path: &Path,
+    target: &Path,
+    metadata: &Metadata,
+    created: i64,
+) -> Fallible<i64> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+INSERT INTO symbolic_links (path, target, created, atime, mtime, uid, gid, user_name, group_name, ctime)
+VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+"#,
+    )?;
+
+    stmt.execute(params![
+        path_as_bytes(path),
+        path_as_bytes(target),
+        created,
+        atime(metadata),
+        mtime(metadata),
+        metadata.uid(),
+        metadata.gid(),
+        user_name(metadata.uid()),
+        group_name(metadata.gid()),
+        ctime(metadata),
+    ])?;
+    let symbolic_link_id = conn.last_insert_rowid();
+
+    Ok(symbolic_link_id)
+}
+
+pub fn update_symbolic_link(conn: &Connection, path: &Path, deleted: i64) -> Fallible {
+    let mut stmt = conn.prepare_cached(
+        "UPDATE symbolic_links SET deleted = ? WHERE path = ? AND deleted IS NULL",
+    )?;
+
+    stmt.execute(params![deleted, path_as_bytes(path)])?;
+
+    Ok(())
+}
+
+pub fn select_symbolic_links_by_path(
+    conn: &Connection,
+    path_filter: Option<&Path>,
+    snapshot_id: Option<i64>,
+    mut consumer: impl FnMut(&Path, &Path, &Attributes) -> Fallible,
+) -> Fallible {
+    let mut stmt = conn.prepare(
+        r#"
+SELECT path, target, uid, gid, user_name, group_name, atime, mtime
+FROM symbolic_links
+WHERE IFNULL(path GLOB ?1, TRUE)
+AND IFNULL(created <= ?2 AND IFNULL(deleted > ?2, TRUE), deleted IS NULL)
+"#,
+    )?;
+
+    let mut rows = stmt.query(params![path_filter.map(path_as_bytes), snapshot_id])?;
+    while let Some(row) = rows.next()? {
+        let path = path_from_blob(row.get_ref_unwrap(0))?;
+        let target = path_from_blob(row.get_ref_unwrap(1))?;
+        let attrs = Attributes::from_row(row, 2)?;
+
+        consumer(path, target, &attrs)?;
+    }
+
+    Ok(())
+}
+
+pub fn select_unchanged_special_file(
+    conn: &Connection,
+    path: &Path,
+    metadata: &Metadata,
+) -> Fallible<Option<i64>> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+SELECT id
+FROM special_files
+WHERE path = ?
+AND mode = ?
+AND rdev = ?
+AND mtime IS ?
+AND ctime IS ?
+AND uid IS ?
+AND gid IS ?
+AND deleted IS NULL
+"#,
+    )?;
+
+    let special_file_id = stmt
+        .query_row(
+            params![
+                path_as_bytes(path),
+                metadata.mode(),
+                metadata.rdev() as i64,
+                mtime(metadata),
+                ctime(metadata),
+                metadata.uid(),
+                metadata.gid(),
+            ],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    Ok(special_file_id)
+}
+
+pub fn insert_special_file(
+    conn: &Connection,
+    path: &Path,
+    metadata: &Metadata,
+    created: i64,
+) -> Fallible<i64> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+INSERT INTO special_files (path, mode, rdev, created, atime, mtime, ctime, uid, gid, user_name, group_name)
+VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+"#,
+    )?;
 
-    stmt.execute(params![size, mode, file_id])?;
+    stmt.execute(params![
+        path_as_bytes(path),
+        metadata.mode(),
+        metadata.rdev() as i64,
+        created,
+        atime(metadata),
+        mtime(metadata),
+        ctime(metadata),
+        metadata.uid(),
+        metadata.gid(),
+        user_name(metadata.uid()),
+        group_name(metadata.gid()),
+    ])?;
+    let special_file_id = conn.last_insert_rowid();
 
-    Ok(())
+    Ok(special_file_id)
 }
 
-pub fn select_files_by_path(
-    conn: &Connection,
-    path_filter: Option<&Path>,
-    mut consumer: impl FnMut(i64, &Path, u64, u32) -> Fallible,
-) -> Fallible {
-    let mut stmt =
-        conn.prepare("SELECT id, path, size, mode FROM files WHERE IFNULL(path GLOB ?, TRUE)")?;
-
-    let mut rows = stmt.query(params![path_filter.map(path_as_bytes)])?;
-    while let Some(row) = rows.next()? {
-        let file_id = row.get(0)?;
-        let path = path_from_blob(row.get_ref_unwrap(1))?;
-        let size = row.get_ref_unwrap(2).as_i64()? as u64;
-        let mode = row.get(3)?;
+pub fn update_special_file(conn: &Connection, path: &Path, deleted: i64) -> Fallible {
+    let mut stmt = conn.prepare_cached(
+        "UPDATE special_files SET deleted = ? WHERE path = ? AND deleted IS NULL",
+    )?;
 
-        consumer(file_id, path, size, mode)?;
-    }
+    stmt.execute(params![deleted, path_as_bytes(path)])?;
 
     Ok(())
 }
 
-pub fn select_files_by_path_and_archive(
+pub fn select_special_files_by_path(
     conn: &Connection,
     path_filter: Option<&Path>,
-    archive_id: i64,
-    mut consumer: impl FnMut(i64, &Path) -> Fallible,
+    snapshot_id: Option<i64>,
+    mut consumer: impl FnMut(&Path, u32, u64, &Attributes) -> Fallible,
 ) -> Fallible {
     let mut stmt = conn.prepare(
         r#"
-SELECT
-    id,
-    path
-FROM files
-WHERE IFNULL(path GLOB ?, TRUE)
-AND id IN (
-    SELECT mappings.file_id
-    FROM mappings, blocks
-    WHERE mappings.block_id = blocks.id
-    AND blocks.archive_id = ?
-)
+SELECT path, mode, rdev, uid, gid, user_name, group_name, atime, mtime
+FROM special_files
+WHERE IFNULL(path GLOB ?1, TRUE)
+AND IFNULL(created <= ?2 AND IFNULL(deleted > ?2, TRUE), deleted IS NULL)
 "#,
     )?;
 
-    let mut rows = stmt.query(params![path_filter.map(path_as_bytes), archive_id])?;
+    let mut rows = stmt.query(params![path_filter.map(path_as_bytes), snapshot_id])?;
     while let Some(row) = rows.next()? {
-        let file_id = row.get(0)?;
-        let path = path_from_blob(row.get_ref_unwrap(1))?;
+        let path = path_from_blob(row.get_ref_unwrap(0))?;
+        let mode = row.get(1)?;
+        let rdev = row.get_ref_unwrap(2).as_i64()? as u64;
+        let attrs = Attributes::from_row(row, 3)?;
 
-        consumer(file_id, path)?;
+        consumer(path, mode, rdev, &attrs)?;
     }
 
     Ok(())
 }
 
-pub fn select_directory(conn: &Connection, path: &Path) -> Fallible<Option<i64>> {
-    let mut stmt = conn.prepare_cached("SELECT id FROM directories WHERE path = ?")?;
-
-    let directory_id = stmt
-        .query_row(params![path_as_bytes(path)], |row| row.get(0))
-        .optional()?;
+pub fn insert_xattr(conn: &Connection, name: &[u8], value: &[u8]) -> Fallible<i64> {
+    let mut stmt =
+        conn.prepare_cached("INSERT OR IGNORE INTO xattrs (name, value) VALUES (?, ?)")?;
 
-    Ok(directory_id)
-}
+    stmt.execute(params![name, value])?;
 
-pub fn insert_directory(conn: &Connection, path: &Path, metadata: &Metadata) -> Fallible<i64> {
-    let mut stmt = conn.prepare_cached("INSERT INTO directories (path, mode) VALUES (?, ?)")?;
+    let mut stmt = conn.prepare_cached("SELECT id FROM xattrs WHERE name = ? AND value = ?")?;
 
-    stmt.execute(params![path_as_bytes(path), metadata.mode()])?;
-    let directory_id = conn.last_insert_rowid();
+    let xattr_id = stmt.query_row(params![name, value], |row| row.get(0))?;
 
-    Ok(directory_id)
+    Ok(xattr_id)
 }
 
-pub fn update_directory(conn: &Connection, directory_id: i64, metadata: &Metadata) -> Fallible {
-    let mut stmt = conn.prepare_cached("UPDATE directories SET mode = ? WHERE id = ?")?;
+pub fn insert_new_file_xattr(conn: &Connection, new_file_id: i64, xattr_id: i64) -> Fallible {
+    let mut stmt =
+        conn.prepare_cached("INSERT INTO new_file_xattrs (new_file_id, xattr_id) VALUES (?, ?)")?;
 
-    stmt.execute(params![metadata.mode(), directory_id])?;
+    stmt.execute(params![new_file_id, xattr_id])?;
 
     Ok(())
 }
 
-pub fn select_directories_by_path(
-    conn: &Connection,
-    path_filter: Option<&Path>,
-    mut consumer: impl FnMut(&Path, u32) -> Fallible,
-) -> Fallible {
-    let mut stmt =
-        conn.prepare("SELECT path, mode FROM directories WHERE IFNULL(path GLOB ?, TRUE)")?;
+pub fn select_hard_link_target(conn: &Connection, new_file_id: i64) -> Fallible<Option<i64>> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+SELECT files.id
+FROM files, new_files
+WHERE new_files.id = ?
+AND files.path = new_files.hard_link
+AND files.size = new_files.size
+AND files.mtime = new_files.mtime
+AND files.ctime = new_files.ctime
+AND files.inode = new_files.inode
+AND files.device = new_files.device
+AND files.deleted IS NULL
+"#,
+    )?;
 
-    let mut rows = stmt.query(params![path_filter.map(path_as_bytes)])?;
-    while let Some(row) = rows.next()? {
-        let path = path_from_blob(row.get_ref_unwrap(0))?;
-        let mode = row.get(1)?;
+    let file_id = stmt
+        .query_row(params![new_file_id], |row| row.get(0))
+        .optional()?;
 
-        consumer(path, mode)?;
-    }
+    Ok(file_id)
+}
+
+pub fn insert_hard_link_mappings(conn: &Connection, file_id: i64, target_id: i64) -> Fallible {
+    let mut stmt = conn.prepare_cached("INSERT INTO mappings (file_id, offset, block_id) SELECT ?, offset, block_id FROM mappings WHERE file_id = ?")?;
+
+    stmt.execute(params![file_id, target_id])?;
 
     Ok(())
 }
 
-pub fn select_symbolic_link(conn: &Connection, path: &Path) -> Fallible<Option<i64>> {
-    let mut stmt = conn.prepare_cached("SELECT id FROM symbolic_links WHERE path = ?")?;
+pub fn insert_file_xattrs(conn: &Connection, file_id: i64, new_file_id: i64) -> Fallible {
+    let mut stmt = conn.prepare_cached("INSERT INTO file_xattrs (file_id, xattr_id) SELECT ?, xattr_id FROM new_file_xattrs WHERE new_file_id = ?")?;
 
-    let symbolic_link_id = stmt
-        .query_row(params![path_as_bytes(path)], |row| row.get(0))
-        .optional()?;
+    stmt.execute(params![file_id, new_file_id])?;
 
-    Ok(symbolic_link_id)
+    Ok(())
 }
 
-pub fn insert_symbolic_link(conn: &Connection, path: &Path, target: &Path) -> Fallible<i64> {
+pub fn insert_directory_xattr(conn: &Connection, directory_id: i64, xattr_id: i64) -> Fallible {
     let mut stmt =
-        conn.prepare_cached("INSERT INTO symbolic_links (path, target) VALUES (?, ?)")?;
+        conn.prepare_cached("INSERT INTO directory_xattrs (directory_id, xattr_id) VALUES (?, ?)")?;
 
-    stmt.execute(params![path_as_bytes(path), path_as_bytes(target)])?;
-    let symbolic_link_id = conn.last_insert_rowid();
+    stmt.execute(params![directory_id, xattr_id])?;
 
-    Ok(symbolic_link_id)
+    Ok(())
 }
 
-pub fn update_symbolic_link(conn: &Connection, symbolic_link_id: i64, target: &Path) -> Fallible {
-    let mut stmt = conn.prepare_cached("UPDATE symbolic_links SET target = ? WHERE id = ?")?;
+pub fn insert_symbolic_link_xattr(
+    conn: &Connection,
+    symbolic_link_id: i64,
+    xattr_id: i64,
+) -> Fallible {
+    let mut stmt = conn.prepare_cached(
+        "INSERT INTO symbolic_link_xattrs (symbolic_link_id, xattr_id) VALUES (?, ?)",
+    )?;
 
-    stmt.execute(params![path_as_bytes(target), symbolic_link_id])?;
+    stmt.execute(params![symbolic_link_id, xattr_id])?;
 
     Ok(())
 }
 
-pub fn select_symbolic_links_by_path(
+pub fn select_xattrs_by_path(
     conn: &Connection,
     path_filter: Option<&Path>,
-    mut consumer: impl FnMut(&Path, &Path) -> Fallible,
+    snapshot_id: Option<i64>,
+    mut consumer: impl FnMut(&Path, &[u8], &[u8]) -> Fallible,
 ) -> Fallible {
-    let mut stmt =
-        conn.prepare("SELECT path, target FROM symbolic_links WHERE IFNULL(path GLOB ?, TRUE)")?;
+    let mut stmt = conn.prepare(
+        r#"
+SELECT files.path, xattrs.name, xattrs.value
+FROM files, file_xattrs, xattrs
+WHERE files.id = file_xattrs.file_id
+AND file_xattrs.xattr_id = xattrs.id
+AND IFNULL(files.path GLOB ?1, TRUE)
+AND IFNULL(files.created <= ?2 AND IFNULL(files.deleted > ?2, TRUE), files.deleted IS NULL)
+UNION ALL
+SELECT directories.path, xattrs.name, xattrs.value
+FROM directories, directory_xattrs, xattrs
+WHERE directories.id = directory_xattrs.directory_id
+AND directory_xattrs.xattr_id = xattrs.id
+AND IFNULL(directories.path GLOB ?1, TRUE)
+AND IFNULL(directories.created <= ?2 AND IFNULL(directories.deleted > ?2, TRUE), directories.deleted IS NULL)
+UNION ALL
+SELECT symbolic_links.path, xattrs.name, xattrs.value
+FROM symbolic_links, symbolic_link_xattrs, xattrs
+WHERE symbolic_links.id = symbolic_link_xattrs.symbolic_link_id
+AND symbolic_link_xattrs.xattr_id = xattrs.id
+AND IFNULL(symbolic_links.path GLOB ?1, TRUE)
+AND IFNULL(symbolic_links.created <= ?2 AND IFNULL(symbolic_links.deleted > ?2, TRUE), symbolic_links.deleted IS NULL)
+"#,
+    )?;
 
-    let mut rows = stmt.query(params![path_filter.map(path_as_bytes)])?;
+    let mut rows = stmt.query(params![path_filter.map(path_as_bytes), snapshot_id])?;
     while let Some(row) = rows.next()? {
         let path = path_from_blob(row.get_ref_unwrap(0))?;
-        let target = path_from_blob(row.get_ref_unwrap(1))?;
+        let name = row.get_ref_unwrap(1).as_blob()?;
+        let value = row.get_ref_unwrap(2).as_blob()?;
 
-        consumer(path, target)?;
+        consumer(path, name, value)?;
     }
 
     Ok(())
 }
 
-pub fn select_block(conn: &Connection, digest: &[u8]) -> Fallible<Option<i64>> {
-    let mut stmt = conn.prepare_cached("SELECT id FROM blocks WHERE digest = ?")?;
+pub fn select_block_digests(
+    conn: &Connection,
+    mut consumer: impl FnMut(i64, &[u8]) -> Fallible,
+) -> Fallible {
+    let mut stmt = conn.prepare("SELECT id, digest FROM blocks")?;
 
-    let block_id = stmt
-        .query_row(params![digest], |row| row.get(0))
-        .optional()?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let block_id = row.get(0)?;
+        let digest = row.get_ref_unwrap(1).as_blob()?;
+
+        consumer(block_id, digest)?;
+    }
+
+    Ok(())
+}
+
+pub fn select_next_block_id(conn: &Connection) -> Fallible<i64> {
+    let block_id = conn.query_row("SELECT IFNULL(MAX(id), 0) + 1 FROM blocks", [], |row| {
+        row.get(0)
+    })?;
 
     Ok(block_id)
 }
 
 pub fn insert_block(
     conn: &Connection,
+    block_id: i64,
     digest: &[u8],
     length: u64,
     archive_id: i64,
     archive_off: u64,
-) -> Fallible<i64> {
+) -> Fallible {
     let mut stmt = conn.prepare_cached(
-        "INSERT INTO blocks (digest, length, archive_id, archive_off) VALUES (?, ?, ?, ?)",
+        "INSERT INTO blocks (id, digest, length, archive_id, archive_off) VALUES (?, ?, ?, ?, ?)",
     )?;
 
     stmt.execute(params![
+        block_id,
         digest,
         length as i64,
         archive_id,
         archive_off as i64
     ])?;
-    let block_id = conn.last_insert_rowid();
 
-    Ok(block_id)
+    Ok(())
 }
 
 pub fn update_block(
@@ -553,14 +1556,6 @@ pub fn insert_mappings(conn: &Connection, file_id: i64, new_file_id: i64) -> Fal
     Ok(())
 }
 
-pub fn delete_mappings(conn: &Connection, file_id: i64) -> Fallible {
-    let mut stmt = conn.prepare_cached("DELETE FROM mappings WHERE file_id = ?")?;
-
-    stmt.execute(params![file_id])?;
-
-    Ok(())
-}
-
 pub fn insert_visited_file(conn: &Connection, file_id: i64) -> Fallible {
     let mut stmt = conn.prepare_cached("INSERT INTO visited_files (file_id) VALUES (?)")?;
 
@@ -587,12 +1582,73 @@ pub fn insert_visited_symbolic_link(conn: &Connection, symbolic_link_id: i64) ->
     Ok(())
 }
 
+pub fn insert_visited_special_file(conn: &Connection, special_file_id: i64) -> Fallible {
+    let mut stmt =
+        conn.prepare_cached("INSERT INTO visited_special_files (special_file_id) VALUES (?)")?;
+
+    stmt.execute(params![special_file_id])?;
+
+    Ok(())
+}
+
+pub fn insert_visited_objects_by_path(conn: &Connection, path: &Path) -> Fallible {
+    let (lower, upper) = path_range(path);
+
+    let mut stmt = conn.prepare_cached(
+        r#"
+INSERT INTO visited_files (file_id)
+SELECT id FROM files
+WHERE (path = ?1 OR (path > ?2 AND path < ?3))
+AND deleted IS NULL
+"#,
+    )?;
+
+    stmt.execute(params![path_as_bytes(path), lower, upper])?;
+
+    let mut stmt = conn.prepare_cached(
+        r#"
+INSERT INTO visited_directories (directory_id)
+SELECT id FROM directories
+WHERE (path = ?1 OR (path > ?2 AND path < ?3))
+AND deleted IS NULL
+"#,
+    )?;
+
+    stmt.execute(params![path_as_bytes(path), lower, upper])?;
+
+    let mut stmt = conn.prepare_cached(
+        r#"
+INSERT INTO visited_symbolic_links (symbolic_link_id)
+SELECT id FROM symbolic_links
+WHERE (path = ?1 OR (path > ?2 AND path < ?3))
+AND deleted IS NULL
+"#,
+    )?;
+
+    stmt.execute(params![path_as_bytes(path), lower, upper])?;
+
+    let mut stmt = conn.prepare_cached(
+        r#"
+INSERT INTO visited_special_files (special_file_id)
+SELECT id FROM special_files
+WHERE (path = ?1 OR (path > ?2 AND path < ?3))
+AND deleted IS NULL
+"#,
+    )?;
+
+    stmt.execute(params![path_as_bytes(path), lower, upper])?;
+
+    Ok(())
+}
+
 pub fn delete_visited_objects(conn: &Connection) -> Fallible {
     conn.execute_batch(
         r#"
+DELETE FROM visited_special_files;
 DELETE FROM visited_symbolic_links;
 DELETE FROM visited_directories;
 DELETE FROM visited_files;
+DELETE FROM new_file_xattrs;
 DELETE FROM new_mappings;
 DELETE FROM new_files;
 "#,
@@ -601,14 +1657,30 @@ DELETE FROM new_files;
     Ok(())
 }
 
-pub fn insert_new_file(conn: &Connection, path: &Path, metadata: &Metadata) -> Fallible<i64> {
-    let mut stmt =
-        conn.prepare_cached("INSERT INTO new_files (path, size, mode) VALUES (?, ?, ?)")?;
+pub fn insert_new_file(
+    conn: &Connection,
+    path: &Path,
+    metadata: &Metadata,
+    hard_link: Option<&Path>,
+) -> Fallible<i64> {
+    let mut stmt = conn.prepare_cached(
+        "INSERT INTO new_files (path, size, mode, mtime, ctime, inode, device, atime, uid, gid, user_name, group_name, hard_link) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+    )?;
 
     stmt.execute(params![
         path_as_bytes(path),
         metadata.size() as i64,
         metadata.mode(),
+        mtime(metadata),
+        ctime(metadata),
+        metadata.ino() as i64,
+        metadata.dev() as i64,
+        atime(metadata),
+        metadata.uid(),
+        metadata.gid(),
+        user_name(metadata.uid()),
+        group_name(metadata.gid()),
+        hard_link.map(path_as_bytes),
     ])?;
     let new_file_id = conn.last_insert_rowid();
 
@@ -670,13 +1742,13 @@ ORDER BY ids.id DESC
     Ok(rows)
 }
 
-pub fn select_unused_archives(conn: &Connection) -> Fallible<Vec<(i64, String)>> {
+pub fn select_unused_archives(conn: &Connection) -> Fallible<Vec<(i64, String, Option<String>)>> {
     let mut stmt = conn.prepare(
-        "SELECT id, b2_file_id FROM archives WHERE id NOT IN (SELECT archive_id FROM blocks)",
+        "SELECT id, b2_file_id, pool_name FROM archives WHERE id NOT IN (SELECT archive_id FROM blocks)",
     )?;
 
     let rows = stmt
-        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
         .collect::<Result<Vec<_>, _>>()?;
 
     Ok(rows)
@@ -693,7 +1765,7 @@ FROM (
         archives.length as length,
         SUM(blocks.length) as blocks_length
     FROM archives, blocks
-    WHERE archives.id = blocks.archive_id
+    WHERE archives.id = blocks.archive_id AND archives.pool_name IS NULL
     GROUP BY archives.id
 )
 WHERE blocks_length < ?
@@ -708,6 +1780,39 @@ ORDER BY length - blocks_length DESC
     Ok(rows)
 }
 
+pub fn select_closed_new_hard_links(
+    conn: &Connection,
+    mut consumer: impl FnMut(i64, &Path, &Path) -> Fallible,
+) -> Fallible {
+    let mut stmt = conn.prepare(
+        r#"
+SELECT
+    new_files.id,
+    new_files.path,
+    new_files.hard_link
+FROM new_files
+WHERE new_files.closed
+AND new_files.hard_link IS NOT NULL
+AND NOT EXISTS (
+    SELECT targets.id
+    FROM new_files targets
+    WHERE targets.path = new_files.hard_link
+)
+"#,
+    )?;
+
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let new_file_id = row.get(0)?;
+        let path = path_from_blob(row.get_ref_unwrap(1))?;
+        let target = path_from_blob(row.get_ref_unwrap(2))?;
+
+        consumer(new_file_id, path, target)?;
+    }
+
+    Ok(())
+}
+
 pub fn select_closed_new_files(
     conn: &Connection,
     mut consumer: impl FnMut(i64, &Path) -> Fallible,
@@ -719,12 +1824,13 @@ SELECT
     new_files.path
 FROM new_files
 WHERE new_files.closed
+AND new_files.hard_link IS NULL
 AND NOT EXISTS (
-    SELECT archives.id
-    FROM new_mappings, blocks, archives
+    SELECT new_mappings.block_id
+    FROM new_mappings
+    LEFT JOIN blocks ON new_mappings.block_id = blocks.id
+    LEFT JOIN archives ON blocks.archive_id = archives.id
     WHERE new_files.id = new_mappings.new_file_id
-    AND new_mappings.block_id = blocks.id
-    AND blocks.archive_id = archives.id
     AND archives.b2_file_id IS NULL
 )
 "#,
@@ -741,33 +1847,116 @@ AND NOT EXISTS (
     Ok(())
 }
 
-pub fn delete_unvisited_files(conn: &Connection) -> Fallible<usize> {
+pub fn update_unvisited_files(conn: &Connection, deleted: i64, path: &Path) -> Fallible<usize> {
+    let (lower, upper) = path_range(path);
+
+    let rows = conn.execute(
+        "UPDATE files SET deleted = ?1 WHERE (path = ?2 OR (path > ?3 AND path < ?4)) AND deleted IS NULL AND id NOT IN (SELECT file_id FROM visited_files)",
+        params![deleted, path_as_bytes(path), lower, upper],
+    )?;
+
+    Ok(rows)
+}
+
+pub fn update_unvisited_directories(
+    conn: &Connection,
+    deleted: i64,
+    path: &Path,
+) -> Fallible<usize> {
+    let (lower, upper) = path_range(path);
+
+    let rows = conn.execute(
+        "UPDATE directories SET deleted = ?1 WHERE (path = ?2 OR (path > ?3 AND path < ?4)) AND deleted IS NULL AND id NOT IN (SELECT directory_id FROM visited_directories)",
+        params![deleted, path_as_bytes(path), lower, upper],
+    )?;
+
+    Ok(rows)
+}
+
+pub fn update_unvisited_symbolic_links(
+    conn: &Connection,
+    deleted: i64,
+    path: &Path,
+) -> Fallible<usize> {
+    let (lower, upper) = path_range(path);
+
+    let rows = conn.execute(
+        "UPDATE symbolic_links SET deleted = ?1 WHERE (path = ?2 OR (path > ?3 AND path < ?4)) AND deleted IS NULL AND id NOT IN (SELECT symbolic_link_id FROM visited_symbolic_links)",
+        params![deleted, path_as_bytes(path), lower, upper],
+    )?;
+
+    Ok(rows)
+}
+
+pub fn update_unvisited_special_files(
+    conn: &Connection,
+    deleted: i64,
+    path: &Path,
+) -> Fallible<usize> {
+    let (lower, upper) = path_range(path);
+
+    let rows = conn.execute(
+        "UPDATE special_files SET deleted = ?1 WHERE (path = ?2 OR (path > ?3 AND path < ?4)) AND deleted IS NULL AND id NOT IN (SELECT special_file_id FROM visited_special_files)",
+        params![deleted, path_as_bytes(path), lower, upper],
+    )?;
+
+    Ok(rows)
+}
+
+pub fn delete_retired_files(conn: &Connection) -> Fallible<usize> {
+    let rows = conn.execute(
+        "DELETE FROM files WHERE deleted <= (SELECT MIN(id) FROM snapshots)",
+        [],
+    )?;
+
+    Ok(rows)
+}
+
+pub fn delete_retired_directories(conn: &Connection) -> Fallible<usize> {
     let rows = conn.execute(
-        "DELETE FROM files WHERE id NOT IN (SELECT file_id FROM visited_files)",
+        "DELETE FROM directories WHERE deleted <= (SELECT MIN(id) FROM snapshots)",
         [],
     )?;
 
     Ok(rows)
 }
 
-pub fn delete_unvisited_directories(conn: &Connection) -> Fallible<usize> {
+pub fn delete_retired_symbolic_links(conn: &Connection) -> Fallible<usize> {
     let rows = conn.execute(
-        "DELETE FROM directories WHERE id NOT IN (SELECT directory_id FROM visited_directories)",
+        "DELETE FROM symbolic_links WHERE deleted <= (SELECT MIN(id) FROM snapshots)",
         [],
     )?;
 
     Ok(rows)
 }
 
-pub fn delete_unvisited_symbolic_links(conn: &Connection) -> Fallible<usize> {
+pub fn delete_retired_special_files(conn: &Connection) -> Fallible<usize> {
     let rows = conn.execute(
-        "DELETE FROM symbolic_links WHERE id NOT IN (SELECT symbolic_link_id FROM visited_symbolic_links)",
+        "DELETE FROM special_files WHERE deleted <= (SELECT MIN(id) FROM snapshots)",
         [],
     )?;
 
     Ok(rows)
 }
 
+pub fn delete_unused_xattrs(conn: &Connection) -> Fallible {
+    conn.execute(
+        r#"
+DELETE FROM xattrs
+WHERE id NOT IN (
+    SELECT xattr_id FROM file_xattrs
+    UNION
+    SELECT xattr_id FROM directory_xattrs
+    UNION
+    SELECT xattr_id FROM symbolic_link_xattrs
+)
+"#,
+        [],
+    )?;
+
+    Ok(())
+}
+
 pub fn delete_unused_blocks(conn: &Connection) -> Fallible<usize> {
     let rows = conn.execute(
         "DELETE FROM blocks WHERE id NOT IN (SELECT block_id FROM mappings)",
@@ -778,7 +1967,7 @@ pub fn delete_unused_blocks(conn: &Connection) -> Fallible<usize> {
 }
 
 pub fn select_storage_used(conn: &Connection) -> Fallible<i64> {
-    let storage_used = conn.query_row("SELECT SUM(b2_length) FROM (SELECT b2_length FROM patchsets UNION ALL SELECT b2_length FROM archives)", [], |row| row.get(0))?;
+    let storage_used = conn.query_row("SELECT IFNULL(SUM(b2_length), 0) FROM (SELECT b2_length FROM patchsets UNION ALL SELECT b2_length FROM archives)", [], |row| row.get(0))?;
 
     Ok(storage_used)
 }
@@ -797,6 +1986,95 @@ pub fn select_uncompressed_size(conn: &Connection) -> Fallible<(i64, i64)> {
     Ok((uncompressed_size_of_archives, uncompressed_size_of_blocks))
 }
 
+pub fn select_object_counts(conn: &Connection) -> Fallible<(i64, i64, i64, i64, i64)> {
+    let counts = conn.query_row(
+        r#"
+SELECT
+    (SELECT COUNT(*) FROM files WHERE deleted IS NULL),
+    (SELECT IFNULL(SUM(size), 0) FROM files WHERE deleted IS NULL),
+    (SELECT COUNT(*) FROM directories WHERE deleted IS NULL),
+    (SELECT COUNT(*) FROM symbolic_links WHERE deleted IS NULL),
+    (SELECT COUNT(*) FROM special_files WHERE deleted IS NULL)
+"#,
+        [],
+        |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+            ))
+        },
+    )?;
+
+    Ok(counts)
+}
+
+pub fn select_storage_counts(conn: &Connection) -> Fallible<(i64, i64, i64, i64)> {
+    let counts = conn.query_row(
+        r#"
+SELECT
+    (SELECT COUNT(*) FROM snapshots),
+    (SELECT COUNT(*) FROM patchsets WHERE b2_file_id IS NOT NULL),
+    (SELECT COUNT(*) FROM archives WHERE b2_file_id IS NOT NULL),
+    (SELECT COUNT(*) FROM blocks)
+"#,
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+    )?;
+
+    Ok(counts)
+}
+
+fn atime(metadata: &Metadata) -> i64 {
+    metadata.atime() * 1_000_000_000 + metadata.atime_nsec()
+}
+
+fn mtime(metadata: &Metadata) -> i64 {
+    metadata.mtime() * 1_000_000_000 + metadata.mtime_nsec()
+}
+
+fn ctime(metadata: &Metadata) -> i64 {
+    metadata.ctime() * 1_000_000_000 + metadata.ctime_nsec()
+}
+
+fn user_name(uid: u32) -> Option<String> {
+    thread_local! {
+        static USER_NAMES: RefCell<HashMap<u32, Option<String>>> = RefCell::default();
+    }
+
+    USER_NAMES.with_borrow_mut(|user_names| {
+        user_names
+            .entry(uid)
+            .or_insert_with(|| {
+                User::from_uid(Uid::from_raw(uid))
+                    .ok()
+                    .flatten()
+                    .map(|user| user.name)
+            })
+            .clone()
+    })
+}
+
+fn group_name(gid: u32) -> Option<String> {
+    thread_local! {
+        static GROUP_NAMES: RefCell<HashMap<u32, Option<String>>> = RefCell::default();
+    }
+
+    GROUP_NAMES.with_borrow_mut(|group_names| {
+        group_names
+            .entry(gid)
+            .or_insert_with(|| {
+                Group::from_gid(Gid::from_raw(gid))
+                    .ok()
+                    .flatten()
+                    .map(|group| group.name)
+            })
+            .clone()
+    })
+}
+
 fn path_from_blob(value: ValueRef<'_>) -> Result<&Path, FromSqlError> {
     value
         .as_blob()
@@ -806,3 +2084,16 @@ fn path_from_blob(value: ValueRef<'_>) -> Result<&Path, FromSqlError> {
 fn path_as_bytes(path: &Path) -> &[u8] {
     path.as_os_str().as_bytes()
 }
+
+fn path_range(path: &Path) -> (Vec<u8>, Vec<u8>) {
+    let mut lower = path_as_bytes(path).to_vec();
+
+    if lower.last() != Some(&b'/') {
+        lower.push(b'/');
+    }
+
+    let mut upper = lower.clone();
+    *upper.last_mut().unwrap() = b'0';
+
+    (lower, upper)
+}
@@ -0,0 +1,120 @@
+/*
+Copyright 2019 Adam Reichold
+
+This file is part of b2_backup.
+
+b2_backup is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+b2_backup is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::fs::{create_dir_all, read, read_dir, remove_file};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use tempfile::NamedTempFile;
+
+use super::{client::Backend, Fallible};
+
+#[derive(Debug, Deserialize)]
+pub struct LocalConfig {
+    path: PathBuf,
+}
+
+pub struct Local<'a> {
+    config: &'a LocalConfig,
+}
+
+impl<'a> Local<'a> {
+    pub fn new(config: &'a LocalConfig) -> Fallible<Self> {
+        if !config.path.is_dir() {
+            return Err(
+                format!("Storage directory {} does not exist", config.path.display()).into(),
+            );
+        }
+
+        Ok(Self { config })
+    }
+}
+
+impl Backend for Local<'_> {
+    fn upload(&self, name: &str, buf: &[u8]) -> Fallible<String> {
+        let path = self.config.path.join(name);
+        let dir = path.parent().unwrap();
+
+        create_dir_all(dir)?;
+
+        let mut file = NamedTempFile::new_in(dir)?;
+        file.write_all(buf)?;
+        file.as_file().sync_all()?;
+        file.persist(&path)?;
+
+        Ok(name.to_owned())
+    }
+
+    fn download(&self, name: &str) -> Fallible<Vec<u8>> {
+        let buf = read(self.config.path.join(name))?;
+
+        Ok(buf)
+    }
+
+    fn list(&self, prefix: &str) -> Fallible<Vec<(String, String, u64)>> {
+        let mut files = Vec::new();
+
+        let dir = match prefix.rfind('/') {
+            Some(pos) => self.config.path.join(&prefix[..pos]),
+            None => self.config.path.clone(),
+        };
+
+        if dir.is_dir() {
+            list_dir(&self.config.path, &dir, prefix, &mut files)?;
+        }
+
+        files.sort_unstable();
+
+        Ok(files)
+    }
+
+    fn remove(&self, name: &str, _id: &str) -> Fallible {
+        remove_file(self.config.path.join(name))?;
+
+        Ok(())
+    }
+}
+
+fn list_dir(
+    root: &Path,
+    dir: &Path,
+    prefix: &str,
+    files: &mut Vec<(String, String, u64)>,
+) -> Fallible {
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let metadata = entry.metadata()?;
+
+        if metadata.is_dir() {
+            list_dir(root, &path, prefix, files)?;
+        } else if metadata.is_file() {
+            let name = path
+                .strip_prefix(root)?
+                .to_str()
+                .ok_or_else(|| format!("Invalid object name {}", path.display()))?;
+
+            if name.starts_with(prefix) {
+                files.push((name.to_owned(), name.to_owned(), metadata.len()));
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,347 @@
+/*
+Copyright 2019 Adam Reichold
+
+This file is part of b2_backup.
+
+b2_backup is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+b2_backup is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::mem::replace;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::process::id as process_id;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    mpsc::{channel, Receiver, RecvTimeoutError},
+    Mutex, PoisonError,
+};
+use std::thread::scope;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use nix::{
+    errno::Errno,
+    fcntl::{Flock, FlockArg},
+    unistd::gethostname,
+};
+
+use super::{client::Client, interrupt, pack::random_id, Config, Fallible};
+
+pub fn with_lock<T>(
+    client: &Client,
+    config: &Config,
+    producer: impl FnOnce(&Lock) -> Fallible<T>,
+) -> Fallible<T> {
+    let lock = Lock::acquire(client, config)?;
+
+    let (sender, receiver) = channel();
+
+    let res = scope(|scope| {
+        let heartbeat = scope.spawn(|| lock.heartbeat(config, receiver));
+
+        let res = producer(&lock);
+
+        drop(sender);
+        heartbeat.join().unwrap();
+
+        res
+    });
+
+    lock.check()?;
+
+    res
+}
+
+pub fn break_lock(client: &Client) -> Fallible {
+    for (name, b2_file_id, _) in client.list("lock_")? {
+        match read_lock(client, &name) {
+            Ok((owner, _expires)) => println!("Breaking lock held by {owner}"),
+            Err(err) => println!("Breaking unreadable lock {name}: {err}"),
+        }
+
+        client.remove(&name, &b2_file_id)?;
+    }
+
+    Ok(())
+}
+
+pub fn lock_manifest(path: &Path) -> Fallible<Flock<File>> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(lock_path(path))?;
+
+    let mut file = match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+        Ok(file) => file,
+        Err((file, Errno::EWOULDBLOCK)) => {
+            return Err(format!("Backup already running (pid {})", read_pid(&file)).into())
+        }
+        Err((_file, err)) => return Err(err.into()),
+    };
+
+    file.set_len(0)?;
+    write!(file, "{}", process_id())?;
+
+    Ok(file)
+}
+
+pub fn manifest_lock_holder(path: &Path) -> Fallible<Option<Pid>> {
+    let file = match File::open(lock_path(path)) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    match Flock::lock(file, FlockArg::LockSharedNonblock) {
+        Ok(_file) => Ok(None),
+        Err((file, Errno::EWOULDBLOCK)) => Ok(Some(read_pid(&file))),
+        Err((_file, err)) => Err(err.into()),
+    }
+}
+
+fn lock_path(path: &Path) -> OsString {
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    lock_path
+}
+
+pub struct Pid(Option<u32>);
+
+impl fmt::Display for Pid {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(pid) => write!(fmt, "{pid}"),
+            None => write!(fmt, "unknown"),
+        }
+    }
+}
+
+fn read_pid(mut file: &File) -> Pid {
+    let mut pid = String::new();
+
+    // The holder might not have written its pid yet.
+    Pid(file
+        .read_to_string(&mut pid)
+        .ok()
+        .and_then(|_| pid.trim().parse().ok()))
+}
+
+pub struct Lock<'a> {
+    client: &'a Client<'a>,
+    token: String,
+    owner: String,
+    object: Mutex<(String, String)>,
+    expires: AtomicU64,
+    lost: AtomicBool,
+}
+
+impl<'a> Lock<'a> {
+    fn acquire(client: &'a Client<'a>, config: &Config) -> Fallible<Self> {
+        let token = random_id()?;
+        let owner = format!(
+            "{} (pid {})",
+            gethostname()?.to_string_lossy(),
+            process_id()
+        );
+
+        let (object, expires) = write_lock(client, config, &token, &owner)?;
+
+        let lock = Self {
+            client,
+            token,
+            owner,
+            object: Mutex::new(object),
+            expires: AtomicU64::new(expires),
+            lost: AtomicBool::new(false),
+        };
+
+        for (name, b2_file_id, _) in client.list("lock_")? {
+            if name.starts_with(&format!("lock_{}_", lock.token)) {
+                continue;
+            }
+
+            let (owner, expires) = match read_lock(client, &name) {
+                Ok(lock) => lock,
+                Err(err) => {
+                    return Err(format!(
+                        "Storage is locked by unreadable lock {name} ({err}), use break-lock if it is stale"
+                    )
+                    .into())
+                }
+            };
+
+            if expires <= now() {
+                println!("Removing stale lock held by {owner}");
+
+                client.remove(&name, &b2_file_id)?;
+                continue;
+            }
+
+            return Err(format!(
+                "Storage is locked by {} for another {} seconds, use break-lock if it is stale",
+                owner,
+                expires.saturating_sub(now())
+            )
+            .into());
+        }
+
+        Ok(lock)
+    }
+
+    pub fn check(&self) -> Fallible {
+        if self.lost.load(Ordering::SeqCst) {
+            return Err("Stopped as the lock could not be renewed before it expired".into());
+        }
+
+        Ok(())
+    }
+
+    fn heartbeat(&self, config: &Config, receiver: Receiver<()>) {
+        let interval = (config.lock_timeout / 3).max(1);
+
+        while let Err(RecvTimeoutError::Timeout) =
+            receiver.recv_timeout(Duration::from_secs(interval))
+        {
+            let (object, expires) = match write_lock(self.client, config, &self.token, &self.owner)
+            {
+                Ok(res) => res,
+                Err(err) => {
+                    eprintln!("Failed to renew lock: {err}");
+
+                    if now() + interval >= self.expires.load(Ordering::SeqCst) {
+                        eprintln!("Stopping as the lock will expire before it can be renewed");
+
+                        self.lost.store(true, Ordering::SeqCst);
+                        interrupt();
+                        return;
+                    }
+
+                    continue;
+                }
+            };
+
+            self.expires.store(expires, Ordering::SeqCst);
+
+            let (name, b2_file_id) = replace(&mut *self.object.lock().unwrap(), object);
+
+            if let Err(err) = self.client.remove(&name, &b2_file_id) {
+                eprintln!("Failed to remove previous lock: {err}");
+            }
+        }
+    }
+}
+
+impl Drop for Lock<'_> {
+    fn drop(&mut self) {
+        let (name, b2_file_id) = &*self.object.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if let Err(err) = self.client.remove(name, b2_file_id) {
+            eprintln!("Failed to release lock: {err}");
+        }
+    }
+}
+
+fn write_lock(
+    client: &Client,
+    config: &Config,
+    token: &str,
+    owner: &str,
+) -> Fallible<((String, String), u64)> {
+    let name = format!("lock_{}_{}", token, random_id()?);
+    let expires = now() + config.lock_timeout;
+
+    let (b2_file_id, _) = client.upload(&name, format!("{owner}\n{expires}").as_bytes())?;
+
+    Ok(((name, b2_file_id), expires))
+}
+
+fn read_lock(client: &Client, name: &str) -> Fallible<(String, u64)> {
+    let mut lock = String::new();
+    client.download(name)?.read_to_string(&mut lock)?;
+
+    let (owner, expires) = lock
+        .rsplit_once('\n')
+        .ok_or_else(|| format!("Lock {name} is malformed"))?;
+
+    Ok((owner.to_owned(), expires.parse()?))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_yaml::from_str;
+    use tempfile::{tempdir, TempDir};
+
+    fn config(store: &TempDir) -> Config {
+        from_str(&format!(
+            r#"
+backend:
+  type: local
+  path: {}
+key: '0000000000000000000000000000000000000000000000000000000000000000'
+"#,
+            store.path().display()
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn lost_lock_stops_producer() {
+        let store = tempdir().unwrap();
+        let config = config(&store);
+        let client = Client::new(&config).unwrap();
+
+        let res = with_lock(&client, &config, |lock| {
+            lock.check()?;
+
+            lock.lost.store(true, Ordering::SeqCst);
+
+            assert!(lock.check().is_err());
+
+            Ok(())
+        });
+
+        assert!(res.is_err());
+        assert!(client.list("lock_").unwrap().is_empty());
+    }
+
+    #[test]
+    fn unreadable_lock_is_held() {
+        let store = tempdir().unwrap();
+        let config = config(&store);
+        let client = Client::new(&config).unwrap();
+
+        client.upload("lock_other_lock", &b"garbage"[..]).unwrap();
+
+        let err = with_lock(&client, &config, |_lock| Ok(())).unwrap_err();
+        assert!(err.to_string().contains("lock_other_lock"));
+
+        break_lock(&client).unwrap();
+
+        with_lock(&client, &config, |_lock| Ok(())).unwrap();
+    }
+}
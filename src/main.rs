@@ -16,13 +16,20 @@ GNU General Public License for more details.
 You should have received a copy of the GNU General Public License
 along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
 */
+mod b2;
 mod backup;
 mod client;
 mod database;
+mod local;
+mod lock;
 mod manifest;
 mod pack;
+mod pool;
+mod s3;
 mod split;
+mod xattr;
 
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::fs::{metadata, read_to_string, set_permissions, File};
@@ -30,9 +37,12 @@ use std::io::{Error as IoError, ErrorKind as IoErrorKind};
 use std::os::unix::fs::{FileExt, PermissionsExt};
 
 use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use clap::{command, value_parser, Arg, ArgMatches, Command};
+use clap::{command, value_parser, Arg, ArgAction, ArgMatches, Command};
 use nix::{
     errno::Errno,
     fcntl::copy_file_range,
@@ -46,60 +56,150 @@ use rayon::{
 use serde::Deserialize;
 use serde_yaml::from_str;
 
-use self::{backup::backup, client::Client, manifest::Manifest, pack::Key};
+use self::{
+    b2::B2Config,
+    backup::{Backup, Budget, ErrorPolicy, ExcludePatterns, Include, Summary},
+    client::{BackendConfig, Client},
+    lock::{break_lock, with_lock},
+    manifest::{BlockIndex, Manifest, RestoreOwner},
+    pack::Key,
+    pool::PoolConfig,
+};
 
 type Fallible<T = ()> = Result<T, Box<dyn Error + Send + Sync>>;
 
-fn main() -> Fallible {
+const COMPLETED_WITH_WARNINGS: u8 = 3;
+
+fn main() -> Fallible<ExitCode> {
     let opts = parse_opts();
 
-    let config = Config::read(get_path(&opts, "config").unwrap())?;
+    let config = Config::read(
+        get_path(&opts, "config").unwrap(),
+        opts.get_one::<String>("profile").map(String::as_str),
+    )?;
 
     let client = Client::new(&config)?;
 
-    let mut manifest = Manifest::open(get_path(&opts, "manifest").unwrap())?;
+    let manifest_path = get_path(&opts, "manifest")
+        .or(config.manifest.as_deref())
+        .unwrap_or(Path::new("manifest.db"));
+
+    let mut manifest = match opts.subcommand_name() {
+        Some("list-snapshots" | "list-files" | "restore-files" | "stats" | "check") => {
+            Manifest::open_read_only(manifest_path)?
+        }
+        _ => Manifest::open(manifest_path)?,
+    };
 
     match opts.subcommand() {
         Some(("backup", args)) => {
-            manifest.update(config.keep_deleted_files, &client, |update| {
-                install_interrupt_handler()?;
-
-                if let Some(num_threads) = config.num_threads {
-                    ThreadPoolBuilder::new()
-                        .num_threads(num_threads)
-                        .build_global()?;
-                }
-
+            let rehash_all = args.get_flag("rehash_all");
+            let tag = args.get_one::<String>("tag").map(String::as_str);
+            let budget = Budget::new(
+                args.get_one::<u64>("max_upload").copied(),
+                args.get_one::<Duration>("max_duration").copied(),
+            );
+
+            let mut summary = Summary::default();
+            let exclude_patterns = ExcludePatterns::new(&config, &mut summary)?;
+            let summary = Mutex::new(summary);
+
+            let delete_unvisited = if config.keep_deleted_files {
                 config
                     .includes
-                    .par_iter()
-                    .try_for_each(|path| backup(&config, &client, update, path))
+                    .iter()
+                    .filter(|include| !include.keep_deleted_files(&config))
+                    .map(Include::path)
+                    .collect()
+            } else {
+                vec![Path::new("/")]
+            };
+
+            with_lock(&client, &config, |lock| {
+                manifest.update(&config, &client, lock, &delete_unvisited, tag, |update| {
+                    install_interrupt_handler()?;
+
+                    if let Some(num_threads) = config.num_threads {
+                        ThreadPoolBuilder::new()
+                            .num_threads(num_threads)
+                            .build_global()?;
+                    }
+
+                    let index = BlockIndex::new(update)?;
+
+                    let backup = Backup {
+                        config: &config,
+                        client: &client,
+                        update,
+                        index: &index,
+                        summary: &summary,
+                        exclude_patterns: &exclude_patterns,
+                        budget: &budget,
+                        rehash_all,
+                        hard_links: Default::default(),
+                    };
+
+                    config
+                        .includes
+                        .par_iter()
+                        .try_for_each(|include| backup.backup_include(include))?;
+
+                    index.finish(update, &config)
+                })?;
+
+                if *args.get_one::<bool>("maybe_collect").unwrap()
+                    && !was_interrupted()
+                    && !budget.is_exceeded(&client)
+                {
+                    manifest.maybe_collect_small_archives(&config, &client, lock)?;
+                    manifest.maybe_collect_small_patchsets(&config, &client, lock)?;
+                }
+
+                Ok(())
             })?;
 
-            if *args.get_one::<bool>("maybe_collect").unwrap() {
-                manifest.maybe_collect_small_archives(&config, &client)?;
-                manifest.maybe_collect_small_patchsets(&config, &client)?;
-            }
+            let summary = summary.into_inner().unwrap();
+            summary.print();
 
-            Ok(())
+            if summary.has_warnings() {
+                return Ok(ExitCode::from(COMPLETED_WITH_WARNINGS));
+            }
+        }
+        Some(("collect-small-archives", _)) => with_lock(&client, &config, |lock| {
+            manifest.collect_small_archives(&config, &client, lock)
+        })?,
+        Some(("collect-small-patchsets", _)) => with_lock(&client, &config, |lock| {
+            manifest.collect_small_patchsets(&config, &client, lock)
+        })?,
+        Some(("list-snapshots", _)) => manifest.list_snapshots()?,
+        Some(("stats", _)) => manifest.stats()?,
+        Some(("list-files", args)) => {
+            manifest.list_files(get_path(args, "filter"), get_snapshot(args))?
         }
-        Some(("collect-small-archives", _)) => manifest.collect_small_archives(&config, &client),
-        Some(("collect-small-patchsets", _)) => manifest.collect_small_patchsets(&config, &client),
-        Some(("list-files", args)) => manifest.list_files(get_path(args, "filter")),
         Some(("restore-files", args)) => manifest.restore_files(
             &client,
             get_path(args, "filter"),
+            get_snapshot(args),
             get_path(args, "target_dir"),
-        ),
-        Some(("restore-manifest", _)) => manifest.restore_manifest(&client),
-        Some(("purge-storage", _)) => manifest.purge_storage(&client),
+            get_restore_owner(args),
+        )?,
+        Some(("restore-manifest", _)) => manifest.restore_manifest(&client)?,
+        Some(("check", args)) => {
+            manifest.check(&client, args.get_one::<u8>("read_data").copied())?
+        }
+        Some(("purge-storage", _)) => with_lock(&client, &config, |lock| {
+            manifest.purge_storage(&client, lock)
+        })?,
+        Some(("break-lock", _)) => break_lock(&client)?,
         None | Some(_) => unreachable!(),
     }
+
+    Ok(ExitCode::SUCCESS)
 }
 
 fn install_interrupt_handler() -> Fallible {
     extern "C" fn handler(_signum: c_int) {
-        INTERRUPTED.store(true, Ordering::SeqCst);
+        interrupt();
     }
 
     unsafe {
@@ -109,6 +209,10 @@ fn install_interrupt_handler() -> Fallible {
     Ok(())
 }
 
+fn interrupt() {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
 fn was_interrupted() -> bool {
     INTERRUPTED.load(Ordering::SeqCst)
 }
@@ -170,56 +274,182 @@ fn parse_opts() -> ArgMatches {
         .arg(
             Arg::new("manifest")
                 .long("manifest")
-                .default_value("manifest.db")
                 .value_parser(value_parser!(PathBuf)),
         )
+        .arg(Arg::new("profile").long("profile").global(true))
         .subcommand_required(true)
         .subcommand(
-            Command::new("backup").arg(
-                Arg::new("maybe_collect")
-                    .long("maybe-collect")
-                    .default_value("true")
-                    .value_parser(value_parser!(bool)),
-            ),
+            Command::new("backup")
+                .arg(
+                    Arg::new("maybe_collect")
+                        .long("maybe-collect")
+                        .default_value("true")
+                        .value_parser(value_parser!(bool)),
+                )
+                .arg(
+                    Arg::new("rehash_all")
+                        .long("rehash-all")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(Arg::new("tag").long("tag"))
+                .arg(
+                    Arg::new("max_upload")
+                        .long("max-upload")
+                        .value_parser(parse_size),
+                )
+                .arg(
+                    Arg::new("max_duration")
+                        .long("max-duration")
+                        .value_parser(parse_duration),
+                ),
         )
         .subcommand(Command::new("collect-small-archives"))
         .subcommand(Command::new("collect-small-patchsets"))
+        .subcommand(Command::new("list-snapshots"))
+        .subcommand(Command::new("stats"))
         .subcommand(
-            Command::new("list-files").arg(Arg::new("filter").value_parser(value_parser!(PathBuf))),
+            Command::new("list-files")
+                .arg(Arg::new("filter").value_parser(value_parser!(PathBuf)))
+                .arg(Arg::new("snapshot").long("snapshot")),
         )
         .subcommand(
             Command::new("restore-files")
                 .arg(Arg::new("filter").value_parser(value_parser!(PathBuf)))
+                .arg(Arg::new("snapshot").long("snapshot"))
                 .arg(
                     Arg::new("target_dir")
                         .long("target-dir")
                         .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("numeric_owner")
+                        .long("numeric-owner")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("no_owner")
+                        .long("no-owner")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("numeric_owner"),
                 ),
         )
         .subcommand(Command::new("restore-manifest"))
+        .subcommand(
+            Command::new("check").arg(
+                Arg::new("read_data")
+                    .long("read-data")
+                    .num_args(0..=1)
+                    .require_equals(true)
+                    .default_missing_value("100")
+                    .value_parser(value_parser!(u8).range(1..=100)),
+            ),
+        )
         .subcommand(Command::new("purge-storage"))
+        .subcommand(Command::new("break-lock"))
         .get_matches()
 }
 
+fn parse_size(arg: &str) -> Result<u64, String> {
+    let value = arg.strip_suffix(['B', 'b']).unwrap_or(arg);
+
+    let (value, factor) = match value.char_indices().last() {
+        Some((idx, 'k' | 'K')) => (&value[..idx], 1 << 10),
+        Some((idx, 'M')) => (&value[..idx], 1 << 20),
+        Some((idx, 'G')) => (&value[..idx], 1 << 30),
+        Some((idx, 'T')) => (&value[..idx], 1 << 40),
+        _ => (value, 1),
+    };
+
+    let value = value
+        .trim()
+        .parse::<u64>()
+        .map_err(|err| format!("Invalid size {arg}: {err}"))?;
+
+    value
+        .checked_mul(factor)
+        .ok_or_else(|| format!("Size {arg} is too large"))
+}
+
+fn parse_duration(arg: &str) -> Result<Duration, String> {
+    let (value, factor) = match arg.char_indices().last() {
+        Some((idx, 's')) => (&arg[..idx], 1),
+        Some((idx, 'm')) => (&arg[..idx], 60),
+        Some((idx, 'h')) => (&arg[..idx], 60 * 60),
+        Some((idx, 'd')) => (&arg[..idx], 24 * 60 * 60),
+        _ => (arg, 1),
+    };
+
+    let value = value
+        .trim()
+        .parse::<u64>()
+        .map_err(|err| format!("Invalid duration {arg}: {err}"))?;
+
+    value
+        .checked_mul(factor)
+        .map(Duration::from_secs)
+        .filter(|duration| Instant::now().checked_add(*duration).is_some())
+        .ok_or_else(|| format!("Duration {arg} is too long"))
+}
+
+fn valid_prefix(prefix: &str) -> bool {
+    !prefix.starts_with('/') && !prefix.split('/').any(|part| part == "..")
+}
+
 fn get_path<'a>(opts: &'a ArgMatches, arg: &str) -> Option<&'a Path> {
     opts.get_one::<PathBuf>(arg).map(PathBuf::as_path)
 }
 
+fn get_snapshot(opts: &ArgMatches) -> Option<&str> {
+    opts.get_one::<String>("snapshot").map(String::as_str)
+}
+
+fn get_restore_owner(opts: &ArgMatches) -> RestoreOwner {
+    if opts.get_flag("no_owner") {
+        RestoreOwner::Skip
+    } else if opts.get_flag("numeric_owner") {
+        RestoreOwner::Numeric
+    } else {
+        RestoreOwner::ByName
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Config {
-    app_key_id: String,
-    app_key: String,
-    bucket_id: String,
-    bucket_name: String,
+    #[serde(flatten)]
+    b2: Option<B2Config>,
+    backend: Option<BackendConfig>,
     key: String,
-    includes: Vec<PathBuf>,
+    #[serde(default)]
+    includes: Vec<Include>,
     #[serde(default)]
     excludes: Vec<PathBuf>,
+    #[serde(default)]
+    exclude_patterns: Vec<String>,
+    #[serde(default)]
+    exclude_from: Vec<PathBuf>,
+    #[serde(default = "Config::def_exclude_caches")]
+    exclude_caches: bool,
+    #[serde(default)]
+    exclude_markers: Vec<String>,
+    #[serde(default)]
+    keep_exclude_markers: bool,
+    #[serde(default)]
+    one_file_system: bool,
+    #[serde(default = "Config::def_xattrs")]
+    xattrs: bool,
+    #[serde(default)]
+    xattr_excludes: Vec<String>,
+    #[serde(default = "Config::def_on_error")]
+    on_error: ErrorPolicy,
     #[serde(default = "Config::def_keep_deleted_files")]
     keep_deleted_files: bool,
+    #[serde(default = "Config::def_keep_snapshots")]
+    keep_snapshots: usize,
     num_threads: Option<usize>,
     #[serde(default = "Config::def_compression_level")]
     compression_level: i32,
+    #[serde(default = "Config::def_chunk_bits")]
+    chunk_bits: u32,
     #[serde(default = "Config::def_min_archive_len")]
     min_archive_len: u64,
     #[serde(default = "Config::def_max_manifest_len")]
@@ -230,31 +460,140 @@ pub struct Config {
     small_archives_lower_limit: usize,
     #[serde(default = "Config::def_small_patchsets_limit")]
     small_patchsets_limit: usize,
+    #[serde(default = "Config::def_checkpoint_archives")]
+    checkpoint_archives: usize,
+    #[serde(default = "Config::def_checkpoint_minutes")]
+    checkpoint_minutes: u64,
+    #[serde(default = "Config::def_upload_threads")]
+    upload_threads: usize,
+    #[serde(default = "Config::def_upload_queue_depth")]
+    upload_queue_depth: usize,
+    #[serde(default)]
+    profiles: HashMap<String, Profile>,
+    #[serde(skip)]
+    manifest: Option<PathBuf>,
+    #[serde(default)]
+    prefix: String,
+    pool: Option<PoolConfig>,
+    #[serde(default = "Config::def_lock_timeout")]
+    lock_timeout: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct Profile {
+    includes: Vec<Include>,
+    #[serde(default)]
+    excludes: Vec<PathBuf>,
+    #[serde(default)]
+    exclude_patterns: Vec<String>,
+    #[serde(default)]
+    exclude_from: Vec<PathBuf>,
+    manifest: Option<PathBuf>,
+    prefix: Option<String>,
 }
 
 impl Config {
-    fn read(path: &Path) -> Fallible<Self> {
+    fn read(path: &Path, profile: Option<&str>) -> Fallible<Self> {
         ensure_restrictive_permissions(path)?;
 
-        let config = from_str(&read_to_string(path)?)?;
+        let mut config: Self = from_str(&read_to_string(path)?)?;
+
+        if config.backend.is_none() {
+            config.backend = config.b2.take().map(BackendConfig::B2);
+        }
+
+        if let Some(name) = profile {
+            let profile = config
+                .profiles
+                .remove(name)
+                .ok_or_else(|| format!("No profile named {name} configured"))?;
+
+            config.includes = profile.includes;
+            config.excludes.extend(profile.excludes);
+            config.exclude_patterns.extend(profile.exclude_patterns);
+            config.exclude_from.extend(profile.exclude_from);
+            config.manifest = Some(
+                profile
+                    .manifest
+                    .unwrap_or_else(|| format!("manifest_{name}.db").into()),
+            );
+            config
+                .prefix
+                .push_str(&profile.prefix.unwrap_or_else(|| format!("{name}/")));
+        }
+
+        if !valid_prefix(&config.prefix) {
+            return Err(format!("Invalid object name prefix {}", config.prefix).into());
+        }
+
+        if let Some(pool) = &config.pool {
+            if pool.prefix.is_empty() || !valid_prefix(&pool.prefix) || pool.prefix == config.prefix
+            {
+                return Err(format!("Invalid block pool prefix {}", pool.prefix).into());
+            }
+        }
+
+        for include in &config.includes {
+            if !(10..=24).contains(&include.chunk_bits(&config)) {
+                return Err(format!(
+                    "Chunk bits for {} must be between 10 and 24",
+                    include.path().display()
+                )
+                .into());
+            }
+        }
+
+        if config.upload_threads == 0 {
+            return Err("At least one upload thread is required".into());
+        }
 
         Ok(config)
     }
 
+    fn backend(&self) -> Fallible<&BackendConfig> {
+        self.backend
+            .as_ref()
+            .ok_or_else(|| "No storage backend configured".into())
+    }
+
     fn key(&self) -> Fallible<Key> {
         let mut key = Key::default();
         hex::decode_to_slice(&self.key, &mut key)?;
         Ok(key)
     }
 
+    fn def_exclude_caches() -> bool {
+        true
+    }
+
+    fn def_xattrs() -> bool {
+        true
+    }
+
+    fn def_on_error() -> ErrorPolicy {
+        ErrorPolicy::Skip
+    }
+
     fn def_keep_deleted_files() -> bool {
         false
     }
 
+    fn def_keep_snapshots() -> usize {
+        30
+    }
+
     fn def_compression_level() -> i32 {
         17
     }
 
+    fn def_chunk_bits() -> u32 {
+        15
+    }
+
+    fn def_lock_timeout() -> u64 {
+        900
+    }
+
     fn def_min_archive_len() -> u64 {
         50_000_000
     }
@@ -274,6 +613,22 @@ impl Config {
     fn def_small_patchsets_limit() -> usize {
         25
     }
+
+    fn def_checkpoint_archives() -> usize {
+        100
+    }
+
+    fn def_checkpoint_minutes() -> u64 {
+        60
+    }
+
+    fn def_upload_threads() -> usize {
+        2
+    }
+
+    fn def_upload_queue_depth() -> usize {
+        2
+    }
 }
 
 struct Bytes(f64);
@@ -295,3 +650,68 @@ impl Display for Bytes {
         write!(fmt, "{factor:.1} {unit}")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("0"), Ok(0));
+        assert_eq!(parse_size("123"), Ok(123));
+        assert_eq!(parse_size("123B"), Ok(123));
+        assert_eq!(parse_size("123b"), Ok(123));
+        assert_eq!(parse_size("2k"), Ok(2 << 10));
+        assert_eq!(parse_size("2K"), Ok(2 << 10));
+        assert_eq!(parse_size("2kB"), Ok(2 << 10));
+        assert_eq!(parse_size("3M"), Ok(3 << 20));
+        assert_eq!(parse_size("3MB"), Ok(3 << 20));
+        assert_eq!(parse_size("2G"), Ok(2 << 30));
+        assert_eq!(parse_size("2Gb"), Ok(2 << 30));
+        assert_eq!(parse_size("5T"), Ok(5 << 40));
+        assert_eq!(parse_size("5 T"), Ok(5 << 40));
+    }
+
+    #[test]
+    fn rejects_invalid_sizes() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("B").is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("GB").is_err());
+        assert!(parse_size("1BB").is_err());
+        assert!(parse_size("1X").is_err());
+        assert!(parse_size("-1").is_err());
+        assert!(parse_size("1.5G").is_err());
+    }
+
+    #[test]
+    fn rejects_overflowing_sizes() {
+        assert_eq!(parse_size("16777215T"), Ok(16_777_215 << 40));
+        assert!(parse_size("16777216T").is_err());
+        assert!(parse_size("99999999999T").is_err());
+        assert!(parse_size("18446744073709551616").is_err());
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30m"), Ok(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(parse_duration("1d"), Ok(Duration::from_secs(24 * 60 * 60)));
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("1w").is_err());
+        assert!(parse_duration("1.5h").is_err());
+    }
+
+    #[test]
+    fn rejects_overflowing_durations() {
+        assert!(parse_duration("18446744073709551615d").is_err());
+        assert!(parse_duration("18446744073709551615").is_err());
+    }
+}
@@ -16,20 +16,41 @@ GNU General Public License for more details.
 You should have received a copy of the GNU General Public License
 along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
 */
-use std::collections::{BTreeMap, HashSet};
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{hash_map::Entry, BTreeMap, HashMap, HashSet};
 use std::env::set_current_dir;
 use std::ffi::CStr;
-use std::fs::{create_dir_all, set_permissions, File, Metadata, OpenOptions, Permissions};
-use std::io::{copy, Read, Seek, Write};
-use std::mem::replace;
-use std::os::unix::fs::{symlink as create_symlink, FileExt, PermissionsExt};
+use std::fs::{
+    create_dir_all, hard_link as create_hard_link, remove_file, set_permissions, File, Metadata,
+    OpenOptions, Permissions,
+};
+use std::io::{copy, ErrorKind, Read, Seek, Write};
+use std::mem::take;
+use std::os::unix::fs::{lchown, symlink as create_symlink, FileExt, PermissionsExt};
 use std::path::Path;
-use std::sync::Mutex;
-
-use blake3::hash;
+use std::sync::{
+    atomic::{AtomicI64, Ordering},
+    mpsc::{sync_channel, Receiver, SyncSender},
+    Mutex,
+};
+use std::thread::scope;
+use std::time::{Duration, Instant};
+
+use blake3::{hash, OUT_LEN as DIGEST_LEN};
+use nix::fcntl::Flock;
+use nix::{
+    fcntl::AT_FDCWD,
+    sys::{
+        stat::{mknod, utimensat, Mode, SFlag, UtimensatFlags},
+        time::TimeSpec,
+    },
+    unistd::{mkfifo, Group, User},
+};
+use rayon::{current_num_threads, current_thread_index};
 use rusqlite::{
     session::{Changegroup, ConflictAction, ConflictType, Session},
-    Connection, TransactionBehavior,
+    Connection, Transaction, TransactionBehavior,
 };
 use tempfile::tempfile;
 
@@ -37,112 +58,210 @@ use super::{
     client::Client,
     copy_file_range_full,
     database::{
-        clear_tables, delete_archive, delete_mappings, delete_new_file, delete_patchset,
-        delete_unused_blocks, delete_unvisited_directories, delete_unvisited_files,
-        delete_unvisited_symbolic_links, delete_visited_objects, insert_block, insert_def_archive,
-        insert_def_patchset, insert_directory, insert_file, insert_mappings, insert_new_file,
-        insert_new_mapping, insert_patchset, insert_symbolic_link, insert_visited_directory,
-        insert_visited_file, insert_visited_symbolic_link, open_connection, select_archive,
-        select_archives_by_path, select_block, select_blocks_by_archive, select_blocks_by_file,
-        select_closed_new_files, select_directories_by_path, select_directory, select_file,
-        select_files_by_path, select_files_by_path_and_archive, select_patchset,
-        select_small_archives, select_small_patchsets, select_storage_used, select_symbolic_link,
-        select_symbolic_links_by_path, select_uncompressed_size, select_unused_archives,
-        update_archive, update_block, update_directory, update_file, update_new_file,
-        update_patchset, update_symbolic_link,
+        clear_tables, delete_archive, delete_expired_snapshots, delete_new_file, delete_patchset,
+        delete_retired_directories, delete_retired_files, delete_retired_special_files,
+        delete_retired_symbolic_links, delete_unused_blocks, delete_unused_xattrs,
+        delete_visited_objects, insert_block, insert_def_archive, insert_def_patchset,
+        insert_directory, insert_directory_xattr, insert_file, insert_file_xattrs,
+        insert_hard_link_mappings, insert_mappings, insert_new_file, insert_new_file_xattr,
+        insert_new_mapping, insert_patchset, insert_pending_patchset,
+        insert_pool_archive_reference, insert_snapshot, insert_special_file, insert_symbolic_link,
+        insert_symbolic_link_xattr, insert_visited_directory, insert_visited_file,
+        insert_visited_objects_by_path, insert_visited_special_file, insert_visited_symbolic_link,
+        insert_xattr, open_connection, open_read_only_connection, select_archive,
+        select_archive_lengths, select_archive_pool_name, select_archives_by_path,
+        select_block_digests, select_blocks_by_archive, select_blocks_by_file,
+        select_closed_new_files, select_closed_new_hard_links, select_directories_by_path,
+        select_files_by_path, select_files_by_path_and_archive, select_hard_link_target,
+        select_latest_snapshot, select_next_block_id, select_next_snapshot, select_object_counts,
+        select_patchset, select_patchset_lengths, select_pending_archives,
+        select_pending_patchsets, select_pool_archive, select_pool_archive_lengths,
+        select_pool_block, select_referenced_pool_archives, select_sampled_archives,
+        select_small_archives, select_small_patchsets, select_snapshot, select_snapshot_by_time,
+        select_snapshot_changes, select_snapshots, select_special_files_by_path,
+        select_storage_counts, select_storage_used, select_symbolic_links_by_path,
+        select_unchanged_directory, select_unchanged_file, select_unchanged_special_file,
+        select_unchanged_symbolic_link, select_uncompressed_size, select_unused_archives,
+        select_xattrs_by_path, update_archive, update_block, update_directory, update_file,
+        update_new_file, update_patchset, update_special_file, update_symbolic_link,
+        update_unvisited_directories, update_unvisited_files, update_unvisited_special_files,
+        update_unvisited_symbolic_links, Attributes,
+    },
+    ensure_restrictive_permissions,
+    lock::{lock_manifest, manifest_lock_holder, Lock, Pid},
+    pool::{
+        insert_index, pool_archive_name, push_index_record, remove_pending_pool_archives,
+        remove_pool_archives, sync_pool, upload_pool_archive, upload_refs, verify_pool_archives,
     },
-    ensure_restrictive_permissions, was_interrupted, Bytes, Config, Fallible,
+    was_interrupted,
+    xattr::{write_xattr, Xattrs},
+    Bytes, Config, Fallible,
 };
 
 pub struct Manifest {
     conn: Connection,
+    _lock: Option<Flock<File>>,
+    running_backup: Option<Pid>,
 }
 
 impl Manifest {
     pub fn open(path: &Path) -> Fallible<Self> {
+        let lock = lock_manifest(path)?;
+
         let conn = open_connection(path)?;
 
         ensure_restrictive_permissions(path)?;
 
-        Ok(Self { conn })
+        Ok(Self {
+            conn,
+            _lock: Some(lock),
+            running_backup: None,
+        })
+    }
+
+    pub fn open_read_only(path: &Path) -> Fallible<Self> {
+        let running_backup = manifest_lock_holder(path)?;
+
+        let conn = open_read_only_connection(path)?;
+
+        Ok(Self {
+            conn,
+            _lock: None,
+            running_backup,
+        })
     }
 
     pub fn update(
         &mut self,
-        keep_unvisited_files: bool,
+        config: &Config,
         client: &Client,
+        lock: &Lock,
+        delete_unvisited: &[&Path],
+        tag: Option<&str>,
         producer: impl FnOnce(&Mutex<Update>) -> Fallible,
     ) -> Fallible {
-        let trans = self
-            .conn
-            .transaction_with_behavior(TransactionBehavior::Exclusive)?;
+        let conn = &self.conn;
 
-        delete_visited_objects(&trans)?;
+        upload_pending_patchsets(conn, client, lock)?;
 
-        let unused_archives;
-        let mut patchset;
+        let trans = Transaction::new_unchecked(conn, TransactionBehavior::Exclusive)?;
 
-        {
-            let mut session = Session::new(&trans)?;
-            session.attach::<&CStr>(None)?;
+        // Pending archives go first so that their pool indexes are not synced.
+        let deleted_pending_archives = delete_pending_archives(conn, client, lock)?;
 
-            let archive_id = insert_def_archive(&trans)?;
+        delete_visited_objects(conn)?;
 
-            let update = Mutex::new(Update {
-                conn: &trans,
-                archive_id,
-                archive_len: 0,
-                blocks: tempfile()?,
-            });
+        let snapshot_id = select_next_snapshot(conn)?;
 
-            producer(&update)?;
+        let pool = client.pool();
 
-            let was_interrupted = was_interrupted();
+        if let Some(pool) = &pool {
+            sync_pool(conn, pool)?;
+        }
 
-            let mut update = update.into_inner().unwrap();
+        let (uploads, receiver) = sync_channel(config.upload_queue_depth);
+
+        let mut update = Update {
+            conn,
+            trans: Some(trans),
+            session: start_session(conn)?,
+            snapshot_id,
+            archives: HashMap::new(),
+            new_pool_archives: HashSet::new(),
+            finished_archives: 0,
+            last_checkpoint: Instant::now(),
+            uploads: Some(uploads),
+        };
 
-            if !was_interrupted && update.archive_len != 0 {
-                let name = format!("archive_{}", update.archive_id);
-                update.blocks.rewind()?;
-                let (b2_file_id, b2_length) = client.upload(&name, &mut update.blocks)?;
+        let checkpoint = if deleted_pending_archives {
+            update.checkpoint(config, client, lock)?
+        } else {
+            None
+        };
 
-                update_archive(
-                    &trans,
-                    update.archive_id,
-                    update.archive_len,
-                    &b2_file_id,
-                    b2_length,
-                )?;
-            } else if was_interrupted || update.archive_id == archive_id {
-                delete_archive(&trans, update.archive_id)?;
+        let update = Mutex::new(update);
+
+        if let Some(checkpoint) = checkpoint {
+            upload_checkpoint(&update, client, lock, checkpoint)?;
+        }
+        let receiver = Mutex::new(Some(receiver));
+
+        scope(|scope| {
+            let uploaders = (0..config.upload_threads)
+                .map(|_| scope.spawn(|| upload_archives(&update, config, client, lock, &receiver)))
+                .collect::<Vec<_>>();
+
+            let res = producer(&update).and_then(|()| finish_archives(&update));
+
+            update.lock().unwrap().uploads = None;
+
+            for uploader in uploaders {
+                uploader.join().unwrap()?;
             }
 
-            collect_closed_new_files(&trans)?;
+            res
+        })?;
+
+        let was_interrupted = was_interrupted();
+
+        let mut update = update.into_inner().unwrap();
 
-            unused_archives =
-                delete_unused_archives(&trans, was_interrupted || keep_unvisited_files)?;
+        collect_closed_new_files(conn, snapshot_id)?;
 
-            patchset = Vec::new();
-            session.patchset_strm(&mut patchset)?;
+        if !was_interrupted && !delete_unvisited.is_empty() {
+            delete_unvisited_objects(conn, snapshot_id, delete_unvisited)?;
         }
 
-        if patchset.is_empty() {
+        if select_snapshot_changes(conn, snapshot_id)? {
+            insert_snapshot(conn, snapshot_id, tag)?;
+
+            println!("Recorded snapshot {snapshot_id}");
+        }
+
+        if let Some(pool) = &pool {
+            if !update.new_pool_archives.is_empty() {
+                upload_refs(pool, lock, config, &select_referenced_pool_archives(conn)?)?;
+                verify_pool_archives(pool, &update.new_pool_archives)?;
+            }
+        }
+
+        let unused_archives = delete_unused_archives(conn, config.keep_snapshots)?;
+        let pool_refs = select_referenced_pool_archives(conn)?;
+
+        if !update.commit(client, lock)? {
             println!("No changes recorded");
             return Ok(());
         }
 
-        upload_patchset(&trans, client, patchset.as_slice())?;
-
-        let storage_used = select_storage_used(&trans)?;
+        let storage_used = select_storage_used(conn)?;
 
         let (uncompressed_size_of_archives, uncompressed_size_of_blocks) =
-            select_uncompressed_size(&trans)?;
+            select_uncompressed_size(conn)?;
 
-        trans.commit()?;
+        let mut unused_pool_archives = Vec::new();
 
-        for (archive_id, b2_file_id) in unused_archives {
-            let name = format!("archive_{archive_id}");
-            client.remove(&name, &b2_file_id)?;
+        for (archive_id, b2_file_id, pool_name) in unused_archives {
+            match pool_name {
+                Some(pool_name) => unused_pool_archives.push((pool_name, b2_file_id)),
+                None => {
+                    let name = format!("archive_{archive_id}");
+
+                    lock.check()?;
+                    client.remove(&name, &b2_file_id)?;
+                }
+            }
+        }
+
+        if !unused_pool_archives.is_empty() {
+            match &pool {
+                Some(pool) => {
+                    remove_pool_archives(pool, lock, config, &pool_refs, unused_pool_archives)?
+                }
+                None => println!(
+                    "Keeping {} unused pool archives as no block pool is configured",
+                    unused_pool_archives.len()
+                ),
+            }
         }
 
         println!(
@@ -155,7 +274,12 @@ impl Manifest {
         Ok(())
     }
 
-    pub fn maybe_collect_small_archives(&mut self, config: &Config, client: &Client) -> Fallible {
+    pub fn maybe_collect_small_archives(
+        &mut self,
+        config: &Config,
+        client: &Client,
+        lock: &Lock,
+    ) -> Fallible {
         let mut small_archives = select_small_archives(&self.conn, config.min_archive_len)?.len();
 
         if small_archives <= config.small_archives_upper_limit
@@ -167,7 +291,7 @@ impl Manifest {
         loop {
             println!("There are {small_archives} small archives. Collection triggered...",);
 
-            self.collect_small_archives(config, client)?;
+            self.collect_small_archives(config, client, lock)?;
 
             small_archives = select_small_archives(&self.conn, config.min_archive_len)?.len();
 
@@ -177,29 +301,35 @@ impl Manifest {
         }
     }
 
-    pub fn collect_small_archives(&mut self, config: &Config, client: &Client) -> Fallible {
-        self.update(true, client, |update| {
+    pub fn collect_small_archives(
+        &mut self,
+        config: &Config,
+        client: &Client,
+        lock: &Lock,
+    ) -> Fallible {
+        self.update(config, client, lock, &[], None, |update| {
             let mut update = update.lock().unwrap();
+            let conn = update.conn;
 
-            let small_archives = select_small_archives(update.conn, config.min_archive_len)?;
+            let small_archives = select_small_archives(conn, config.min_archive_len)?;
 
             if small_archives.len() <= 1 {
                 return Err("Not enough small archives".into());
             }
 
+            let archive = update.archive(config, config.compression_level)?;
+
             let mut buffer = Vec::new();
 
             for archive_id in &small_archives {
-                let name = format!("archive_{archive_id}");
-                let mut archive = tempfile()?;
-                copy(&mut client.download(&name)?, &mut archive)?;
+                let small_archive = download_archive(conn, client, *archive_id)?;
 
-                let blocks = select_blocks_by_archive(update.conn, *archive_id)?;
+                let blocks = select_blocks_by_archive(conn, *archive_id)?;
 
                 for (block_id, stored_digest, length, archive_off) in blocks {
                     buffer.resize(length as _, 0);
-                    archive.read_exact_at(&mut buffer, archive_off)?;
-                    update.blocks.write_all(&buffer)?;
+                    small_archive.read_exact_at(&mut buffer, archive_off)?;
+                    archive.blocks.write_all(&buffer)?;
 
                     let digest = hash(&buffer);
                     if digest != stored_digest {
@@ -212,11 +342,17 @@ impl Manifest {
                         .into());
                     }
 
-                    update_block(update.conn, block_id, update.archive_id, update.archive_len)?;
-                    update.archive_len += length;
+                    update_block(conn, block_id, archive.archive_id, archive.archive_len)?;
+                    push_index_record(
+                        &mut archive.index,
+                        &stored_digest,
+                        length,
+                        archive.archive_len,
+                    );
+                    archive.archive_len += length;
                 }
 
-                if update.archive_len >= config.min_archive_len {
+                if archive.archive_len >= config.min_archive_len {
                     break;
                 }
             }
@@ -225,7 +361,12 @@ impl Manifest {
         })
     }
 
-    pub fn maybe_collect_small_patchsets(&mut self, config: &Config, client: &Client) -> Fallible {
+    pub fn maybe_collect_small_patchsets(
+        &mut self,
+        config: &Config,
+        client: &Client,
+        lock: &Lock,
+    ) -> Fallible {
         loop {
             let small_patchsets =
                 select_small_patchsets(&self.conn, config.max_manifest_len)?.len();
@@ -237,11 +378,18 @@ impl Manifest {
 
             println!("There are {small_patchsets} small patchsets. Collection triggered...",);
 
-            self.collect_small_patchsets(config, client)?;
+            self.collect_small_patchsets(config, client, lock)?;
         }
     }
 
-    pub fn collect_small_patchsets(&mut self, config: &Config, client: &Client) -> Fallible {
+    pub fn collect_small_patchsets(
+        &mut self,
+        config: &Config,
+        client: &Client,
+        lock: &Lock,
+    ) -> Fallible {
+        upload_pending_patchsets(&self.conn, client, lock)?;
+
         let trans = self
             .conn
             .transaction_with_behavior(TransactionBehavior::Exclusive)?;
@@ -264,62 +412,111 @@ impl Manifest {
         let mut patchset = Vec::new();
         changegroup.output_strm(&mut patchset)?;
 
-        upload_patchset(&trans, client, patchset.as_slice())?;
+        upload_patchset(&trans, client, lock, patchset.as_slice())?;
 
         for (patchset_id, _) in &small_patchsets {
             delete_patchset(&trans, *patchset_id)?;
         }
 
+        lock.check()?;
         trans.commit()?;
 
         for (patchset_id, b2_file_id) in &small_patchsets {
             let name = format!("manifest_{patchset_id}");
+
+            lock.check()?;
             client.remove(&name, b2_file_id)?;
         }
 
         Ok(())
     }
 
-    pub fn list_files(&mut self, path_filter: Option<&Path>) -> Fallible {
+    pub fn list_snapshots(&mut self) -> Fallible {
+        select_snapshots(&self.conn, |snapshot_id, time, tag| {
+            println!("{:>8} {} {}", snapshot_id, time, tag.unwrap_or_default());
+
+            Ok(())
+        })
+    }
+
+    pub fn stats(&mut self) -> Fallible {
+        let trans = self.conn.transaction()?;
+
+        let (snapshots, patchsets, archives, blocks) = select_storage_counts(&trans)?;
+        let (files, size, directories, symbolic_links, special_files) =
+            select_object_counts(&trans)?;
+
+        let storage_used = select_storage_used(&trans)?;
+        let (uncompressed_size_of_archives, uncompressed_size_of_blocks) =
+            select_uncompressed_size(&trans)?;
+
+        println!("{:<16}{}", "Snapshots:", snapshots);
+        println!("{:<16}{} ({})", "Files:", files, Bytes(size as _));
+        println!("{:<16}{}", "Directories:", directories);
+        println!("{:<16}{}", "Symbolic links:", symbolic_links);
+        println!("{:<16}{}", "Special files:", special_files);
+        println!("{:<16}{}", "Blocks:", blocks);
+        println!("{:<16}{}", "Archives:", archives);
+        println!("{:<16}{}", "Patchsets:", patchsets);
+        println!(
+            "{:<16}{} ({} uncompressed, {} mapped)",
+            "Storage used:",
+            Bytes(storage_used as _),
+            Bytes(uncompressed_size_of_archives as _),
+            Bytes(uncompressed_size_of_blocks as _)
+        );
+
+        Ok(())
+    }
+
+    pub fn list_files(&mut self, path_filter: Option<&Path>, snapshot: Option<&str>) -> Fallible {
         let mut archives = HashSet::new();
         let mut blocks = 0;
 
         let trans = self.conn.transaction()?;
 
-        select_files_by_path(&trans, path_filter, |file_id, path, size, _mode| {
-            archives.clear();
-            blocks = 0;
+        let snapshot_id = resolve_snapshot(&trans, snapshot)?;
 
-            select_blocks_by_file(
-                &trans,
-                file_id,
-                None,
-                |_length, archive_id, _archive_off, _offset| {
-                    archives.insert(archive_id);
-                    blocks += 1;
+        select_files_by_path(
+            &trans,
+            path_filter,
+            snapshot_id,
+            |file_id, path, size, _mode, _attrs, _hard_link| {
+                archives.clear();
+                blocks = 0;
 
-                    Ok(())
-                },
-            )?;
+                select_blocks_by_file(
+                    &trans,
+                    file_id,
+                    None,
+                    |_length, archive_id, _archive_off, _offset| {
+                        archives.insert(archive_id);
+                        blocks += 1;
 
-            println!(
-                "{:>11} {:>8} {:>8} {}",
-                Bytes(size as _).to_string(),
-                archives.len(),
-                blocks,
-                path.display()
-            );
+                        Ok(())
+                    },
+                )?;
 
-            Ok(())
-        })?;
+                println!(
+                    "{:>11} {:>8} {:>8} {}",
+                    Bytes(size as _).to_string(),
+                    archives.len(),
+                    blocks,
+                    path.display()
+                );
 
-        select_directories_by_path(&trans, path_filter, |path, _mode| {
+                Ok(())
+            },
+        )?;
+
+        select_directories_by_path(&trans, path_filter, snapshot_id, |path, _mode, _attrs| {
             let mut files = 0;
 
             select_files_by_path(
                 &trans,
                 Some(&path.join("*")),
-                |_file_id, _path, _size, _mode| {
+                snapshot_id,
+                |_file_id, _path, _size, _mode, _attrs, _hard_link| {
                     files += 1;
 
                     Ok(())
@@ -331,11 +528,27 @@ impl Manifest {
             Ok(())
         })?;
 
-        select_symbolic_links_by_path(&trans, path_filter, |path, _target| {
-            println!("{:>11} {:>8} {:>8} {}", "symlink", "", "", path.display());
+        select_symbolic_links_by_path(
+            &trans,
+            path_filter,
+            snapshot_id,
+            |path, _target, _attrs| {
+                println!("{:>11} {:>8} {:>8} {}", "symlink", "", "", path.display());
 
-            Ok(())
-        })?;
+                Ok(())
+            },
+        )?;
+
+        select_special_files_by_path(
+            &trans,
+            path_filter,
+            snapshot_id,
+            |path, _mode, _rdev, _attrs| {
+                println!("{:>11} {:>8} {:>8} {}", "special", "", "", path.display());
+
+                Ok(())
+            },
+        )?;
 
         Ok(())
     }
@@ -344,7 +557,9 @@ impl Manifest {
         &mut self,
         client: &Client,
         path_filter: Option<&Path>,
+        snapshot: Option<&str>,
         target_dir: Option<&Path>,
+        owner: RestoreOwner,
     ) -> Fallible {
         if let Some(dir) = target_dir {
             if let Some(parent) = dir.parent() {
@@ -356,19 +571,42 @@ impl Manifest {
 
         let trans = self.conn.transaction()?;
 
-        select_files_by_path(&trans, path_filter, |_file_id, path, size, _mode| {
-            let path = path.strip_prefix("/")?;
+        let snapshot_id = resolve_snapshot(&trans, snapshot)?;
 
-            if let Some(parent) = path.parent() {
-                create_dir_all(parent)?;
-            }
+        let mut hard_links = Vec::new();
 
-            File::create(path)?.set_len(size)?;
+        select_files_by_path(
+            &trans,
+            path_filter,
+            snapshot_id,
+            |_file_id, path, size, _mode, _attrs, hard_link| {
+                let path = path.strip_prefix("/")?;
 
-            Ok(())
-        })?;
+                if let Some(parent) = path.parent() {
+                    create_dir_all(parent)?;
+                }
+
+                if let Some(target) = hard_link {
+                    hard_links.push((path.to_owned(), target.strip_prefix("/")?.to_owned()));
+                } else {
+                    File::create(path)?.set_len(size)?;
+                }
+
+                Ok(())
+            },
+        )?;
+
+        for (path, target) in hard_links {
+            match remove_file(&path) {
+                Ok(()) => (),
+                Err(err) if err.kind() == ErrorKind::NotFound => (),
+                Err(err) => return Err(err.into()),
+            }
+
+            create_hard_link(target, path)?;
+        }
 
-        select_directories_by_path(&trans, path_filter, |path, _mode| {
+        select_directories_by_path(&trans, path_filter, snapshot_id, |path, _mode, _attrs| {
             let path = path.strip_prefix("/")?;
 
             create_dir_all(path)?;
@@ -378,56 +616,136 @@ impl Manifest {
 
         let mut buf = Vec::new();
 
-        select_archives_by_path(&trans, path_filter, |archive_id| {
-            let name = format!("archive_{archive_id}");
-            let mut archive = tempfile()?;
-            copy(&mut client.download(&name)?, &mut archive)?;
+        select_archives_by_path(&trans, path_filter, snapshot_id, |archive_id| {
+            let archive = download_archive(&trans, client, archive_id)?;
 
-            select_files_by_path_and_archive(&trans, path_filter, archive_id, |file_id, path| {
-                println!("Restoring {}...", path.display());
+            select_files_by_path_and_archive(
+                &trans,
+                path_filter,
+                snapshot_id,
+                archive_id,
+                |file_id, path| {
+                    println!("Restoring {}...", path.display());
+
+                    let path = path.strip_prefix("/")?;
+                    let file = OpenOptions::new().write(true).open(path)?;
+
+                    select_blocks_by_file(
+                        &trans,
+                        file_id,
+                        Some(archive_id),
+                        |length, _archive_id, archive_off, offset| {
+                            copy_file_range_full(
+                                &mut buf,
+                                &archive,
+                                archive_off,
+                                &file,
+                                offset,
+                                length,
+                            )
+                        },
+                    )
+                },
+            )
+        })?;
 
+        select_files_by_path(
+            &trans,
+            path_filter,
+            snapshot_id,
+            |_file_id, path, _size, mode, attrs, _hard_link| {
                 let path = path.strip_prefix("/")?;
-                let file = OpenOptions::new().write(true).open(path)?;
 
-                select_blocks_by_file(
-                    &trans,
-                    file_id,
-                    Some(archive_id),
-                    |length, _archive_id, archive_off, offset| {
-                        copy_file_range_full(&mut buf, &archive, archive_off, &file, offset, length)
-                    },
-                )
-            })
-        })?;
+                restore_owner(path, attrs, owner)?;
+                set_permissions(path, Permissions::from_mode(mode))?;
+                restore_times(path, attrs)?;
 
-        select_files_by_path(&trans, path_filter, |_file_id, path, _size, mode| {
+                Ok(())
+            },
+        )?;
+
+        select_symbolic_links_by_path(&trans, path_filter, snapshot_id, |path, target, attrs| {
             let path = path.strip_prefix("/")?;
 
-            set_permissions(path, Permissions::from_mode(mode))?;
+            if let Some(parent) = path.parent() {
+                create_dir_all(parent)?;
+            }
+
+            create_symlink(target, path)?;
+
+            restore_owner(path, attrs, owner)?;
+            restore_times(path, attrs)?;
 
             Ok(())
         })?;
 
-        select_directories_by_path(&trans, path_filter, |path, mode| {
+        select_special_files_by_path(
+            &trans,
+            path_filter,
+            snapshot_id,
+            |path, mode, rdev, attrs| {
+                let path = path.strip_prefix("/")?;
+
+                if let Some(parent) = path.parent() {
+                    create_dir_all(parent)?;
+                }
+
+                let kind = SFlag::from_bits_truncate(mode & SFlag::S_IFMT.bits());
+                let perm = Mode::from_bits_truncate(mode);
+
+                let res = if kind == SFlag::S_IFIFO {
+                    mkfifo(path, perm)
+                } else {
+                    mknod(path, kind, perm, rdev)
+                };
+
+                if let Err(err) = res {
+                    eprintln!("Failed to restore special file {}: {}", path.display(), err);
+
+                    return Ok(());
+                }
+
+                restore_owner(path, attrs, owner)?;
+                set_permissions(path, Permissions::from_mode(mode))?;
+                restore_times(path, attrs)?;
+
+                Ok(())
+            },
+        )?;
+
+        select_xattrs_by_path(&trans, path_filter, snapshot_id, |path, name, value| {
             let path = path.strip_prefix("/")?;
 
-            set_permissions(path, Permissions::from_mode(mode))?;
+            if let Err(err) = write_xattr(path, name, value) {
+                eprintln!(
+                    "Failed to restore extended attribute {} of {}: {}",
+                    String::from_utf8_lossy(name),
+                    path.display(),
+                    err
+                );
+            }
 
             Ok(())
         })?;
 
-        select_symbolic_links_by_path(&trans, path_filter, |path, target| {
-            let path = path.strip_prefix("/")?;
+        let mut dirs = Vec::new();
 
-            if let Some(parent) = path.parent() {
-                create_dir_all(parent)?;
-            }
+        select_directories_by_path(&trans, path_filter, snapshot_id, |path, mode, attrs| {
+            let path = path.strip_prefix("/")?;
 
-            create_symlink(path, target)?;
+            dirs.push((path.to_owned(), mode, attrs.clone()));
 
             Ok(())
         })?;
 
+        dirs.sort_unstable_by_key(|(path, _mode, _attrs)| Reverse(path.components().count()));
+
+        for (path, mode, attrs) in dirs {
+            restore_owner(&path, &attrs, owner)?;
+            set_permissions(&path, Permissions::from_mode(mode))?;
+            restore_times(&path, &attrs)?;
+        }
+
         Ok(())
     }
 
@@ -463,7 +781,121 @@ impl Manifest {
         Ok(())
     }
 
-    pub fn purge_storage(&mut self, client: &Client) -> Fallible {
+    pub fn check(&mut self, client: &Client, read_data: Option<u8>) -> Fallible {
+        let mut problems = 0;
+        let mut missing_archives = HashSet::new();
+
+        let trans = self.conn.transaction()?;
+
+        let objects = [
+            ("manifest_", select_patchset_lengths(&trans)?),
+            ("archive_", select_archive_lengths(&trans)?),
+        ];
+
+        for (prefix, lengths) in objects {
+            let mut lengths = lengths.into_iter().collect::<HashMap<_, _>>();
+
+            for (name, _, length) in client.list(prefix)? {
+                let expected_length = name
+                    .trim_start_matches(prefix)
+                    .parse::<i64>()
+                    .ok()
+                    .and_then(|id| lengths.remove(&id));
+
+                match expected_length {
+                    None => match &self.running_backup {
+                        Some(pid) => println!(
+                            "Object {name} is not recorded yet, presumably uploaded by the backup running as pid {pid}."
+                        ),
+                        None => {
+                            println!("Object {name} is orphaned.");
+                            problems += 1;
+                        }
+                    },
+                    Some(expected_length) if expected_length != length => {
+                        println!(
+                            "Object {name} has length {length}, but should have {expected_length}."
+                        );
+                        problems += 1;
+                    }
+                    Some(_) => (),
+                }
+            }
+
+            let mut missing = lengths.into_keys().collect::<Vec<_>>();
+            missing.sort_unstable();
+
+            for id in missing {
+                println!("Object {prefix}{id} is missing.");
+                problems += 1;
+
+                if prefix == "archive_" {
+                    missing_archives.insert(id);
+                }
+            }
+        }
+
+        let pool_archives = select_pool_archive_lengths(&trans)?;
+
+        if !pool_archives.is_empty() {
+            let lengths = match client.pool() {
+                Some(pool) => pool
+                    .list("archive_")?
+                    .into_iter()
+                    .map(|(name, _, length)| (name, length))
+                    .collect::<HashMap<_, _>>(),
+                None => {
+                    return Err("Manifest references a block pool which is not configured".into())
+                }
+            };
+
+            for (archive_id, name, expected_length) in pool_archives {
+                match lengths.get(&name) {
+                    None => {
+                        println!("Pool object {name} is missing.");
+                        problems += 1;
+
+                        missing_archives.insert(archive_id);
+                    }
+                    Some(&length) if length != expected_length => {
+                        println!(
+                            "Pool object {name} has length {length}, but should have {expected_length}."
+                        );
+                        problems += 1;
+                    }
+                    Some(_) => (),
+                }
+            }
+        }
+
+        if let Some(percent) = read_data {
+            let mut buffer = Vec::new();
+
+            for archive_id in select_sampled_archives(&trans, percent)? {
+                if missing_archives.contains(&archive_id) {
+                    continue;
+                }
+
+                match check_archive(&trans, client, archive_id, &mut buffer) {
+                    Ok(corrupted_blocks) => problems += corrupted_blocks,
+                    Err(err) => {
+                        println!("Failed to read archive_{archive_id}: {err}");
+                        problems += 1;
+                    }
+                }
+            }
+        }
+
+        if problems != 0 {
+            return Err(format!("Check found {problems} problems").into());
+        }
+
+        println!("Check found no problems");
+
+        Ok(())
+    }
+
+    pub fn purge_storage(&mut self, client: &Client, lock: &Lock) -> Fallible {
         let trans = self
             .conn
             .transaction_with_behavior(TransactionBehavior::Exclusive)?;
@@ -472,6 +904,7 @@ impl Manifest {
             let patchset_id = name.trim_start_matches("manifest_").parse()?;
 
             if !select_patchset(&trans, patchset_id)? {
+                lock.check()?;
                 client.remove(&name, &b2_file_id)?;
             }
         }
@@ -480,6 +913,7 @@ impl Manifest {
             let archive_id = name.trim_start_matches("archive_").parse()?;
 
             if !select_archive(&trans, archive_id)? {
+                lock.check()?;
                 client.remove(&name, &b2_file_id)?;
             }
         }
@@ -490,135 +924,795 @@ impl Manifest {
 
 pub struct Update<'a> {
     conn: &'a Connection,
+    trans: Option<Transaction<'a>>,
+    session: Session<'a>,
+    snapshot_id: i64,
+    archives: HashMap<i32, Archive>,
+    new_pool_archives: HashSet<String>,
+    finished_archives: usize,
+    last_checkpoint: Instant,
+    uploads: Option<SyncSender<(i32, Archive)>>,
+}
+
+struct Archive {
     archive_id: i64,
+    pool_name: Option<String>,
     archive_len: u64,
     blocks: File,
+    index: Vec<u8>,
+    new_blocks: Vec<(i64, [u8; DIGEST_LEN], u64, u64)>,
 }
 
-unsafe impl Send for Update<'_> {}
+pub struct BlockIndex {
+    shards: Vec<Mutex<HashMap<[u8; DIGEST_LEN], i64>>>,
+    next_block_id: AtomicI64,
+    archives: Vec<Mutex<HashMap<i32, Archive>>>,
+}
 
-impl Update<'_> {
-    pub fn open_file(&self, path: &Path, metadata: &Metadata) -> Fallible<i64> {
-        insert_new_file(self.conn, path, metadata)
-    }
+impl BlockIndex {
+    pub fn new(update: &Mutex<Update>) -> Fallible<Self> {
+        let update = update.lock().unwrap();
 
-    pub fn close_file(&self, new_file_id: i64) -> Fallible {
-        update_new_file(self.conn, new_file_id)
-    }
+        let mut shards = (0..INDEX_SHARDS)
+            .map(|_| HashMap::new())
+            .collect::<Vec<_>>();
 
-    pub fn directory(&self, path: &Path, metadata: &Metadata) -> Fallible {
-        let dir_id = if let Some(dir_id) = select_directory(self.conn, path)? {
-            update_directory(self.conn, dir_id, metadata)?;
+        select_block_digests(update.conn, |block_id, digest| {
+            let digest: [u8; DIGEST_LEN] = digest
+                .try_into()
+                .map_err(|_| format!("Digest of block {block_id} is malformed"))?;
 
-            dir_id
-        } else {
-            insert_directory(self.conn, path, metadata)?
-        };
+            shards[shard(&digest)].insert(digest, block_id);
 
-        insert_visited_directory(self.conn, dir_id)?;
+            Ok(())
+        })?;
 
-        Ok(())
+        Ok(Self {
+            shards: shards.into_iter().map(Mutex::new).collect(),
+            next_block_id: AtomicI64::new(select_next_block_id(update.conn)?),
+            archives: (0..=current_num_threads())
+                .map(|_| Mutex::default())
+                .collect(),
+        })
     }
 
-    pub fn symlink(&self, path: &Path, target: &Path) -> Fallible {
-        let symlink_id = if let Some(symlink_id) = select_symbolic_link(self.conn, path)? {
-            update_symbolic_link(self.conn, symlink_id, target)?;
-
-            symlink_id
-        } else {
-            insert_symbolic_link(self.conn, path, target)?
-        };
+    pub fn finish(&self, update: &Mutex<Update>, config: &Config) -> Fallible {
+        let mut tails = HashMap::<i32, Vec<Archive>>::new();
 
-        insert_visited_symbolic_link(self.conn, symlink_id)?;
+        for archives in &self.archives {
+            for (compression_level, archive) in take(&mut *archives.lock().unwrap()) {
+                tails.entry(compression_level).or_default().push(archive);
+            }
+        }
 
-        Ok(())
-    }
-}
+        for (compression_level, archives) in tails {
+            let mut merged: Option<Archive> = None;
 
-fn collect_closed_new_files(conn: &Connection) -> Fallible {
-    select_closed_new_files(conn, |new_file_id, path| {
-        let file_id = if let Some(file_id) = select_file(conn, path)? {
-            update_file(conn, file_id, new_file_id)?;
-            delete_mappings(conn, file_id)?;
+            for archive in archives {
+                let mut target = match merged.take() {
+                    Some(target) => target,
+                    None => {
+                        merged = Some(archive);
+                        continue;
+                    }
+                };
 
-            file_id
-        } else {
-            insert_file(conn, new_file_id)?
-        };
+                let archive_id = archive.archive_id;
+                target.append(archive)?;
+                delete_archive(update.lock().unwrap().conn, archive_id)?;
 
-        insert_mappings(conn, file_id, new_file_id)?;
-        insert_visited_file(conn, file_id)?;
+                if target.archive_len >= config.min_archive_len {
+                    queue_archive(update, compression_level, target)?;
+                } else {
+                    merged = Some(target);
+                }
+            }
 
-        delete_new_file(conn, new_file_id)?;
+            if let Some(archive) = merged {
+                queue_archive(update, compression_level, archive)?;
+            }
+        }
 
         Ok(())
-    })
-}
-
-pub fn store_block(
-    update: &Mutex<Update>,
-    config: &Config,
-    client: &Client,
-    new_file_id: i64,
-    offset: u64,
-    block: &[u8],
-) -> Fallible {
-    let digest = hash(block);
+    }
 
-    let archive_id;
-    let archive_len;
-    let mut blocks;
+    fn worker_archives(&self) -> &Mutex<HashMap<i32, Archive>> {
+        let fallback = self.archives.len() - 1;
 
-    {
-        let mut update = update.lock().unwrap();
+        &self.archives[current_thread_index().map_or(fallback, |idx| idx.min(fallback))]
+    }
 
-        let block_id = select_block(update.conn, digest.as_bytes())?;
+    fn get_or_reserve(&self, digest: &[u8; DIGEST_LEN]) -> (i64, bool) {
+        match self.shards[shard(digest)].lock().unwrap().entry(*digest) {
+            Entry::Occupied(entry) => (*entry.get(), false),
+            Entry::Vacant(entry) => {
+                let block_id = self.next_block_id.fetch_add(1, Ordering::Relaxed);
 
-        if let Some(block_id) = block_id {
-            return insert_new_mapping(update.conn, new_file_id, offset, block_id);
+                (*entry.insert(block_id), true)
+            }
         }
+    }
+}
+
+fn shard(digest: &[u8; DIGEST_LEN]) -> usize {
+    digest[0] as usize % INDEX_SHARDS
+}
+
+const INDEX_SHARDS: usize = 64;
+
+// SAFETY: The session, transaction and prepared statements refer to a connection
+// opened in SQLite's multi-thread mode. An update is only shared with the uploader
+// threads behind a mutex, so the connection is never used by two threads at once.
+unsafe impl Send for Update<'_> {}
+
+impl Update<'_> {
+    fn archive(&mut self, config: &Config, compression_level: i32) -> Fallible<&mut Archive> {
+        match self.archives.entry(compression_level) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => Ok(entry.insert(new_archive(self.conn, config)?)),
+        }
+    }
 
-        let length = block.len().try_into().unwrap();
-        let block_id = insert_block(
-            update.conn,
-            digest.as_bytes(),
-            length,
-            update.archive_id,
-            update.archive_len,
+    fn finish_archive(&mut self, archive: &Archive, b2_file_id: &str, b2_length: u64) -> Fallible {
+        update_archive(
+            self.conn,
+            archive.archive_id,
+            archive.archive_len,
+            b2_file_id,
+            b2_length,
+            archive.pool_name.as_deref(),
         )?;
 
-        insert_new_mapping(update.conn, new_file_id, offset, block_id)?;
+        for (block_id, digest, length, archive_off) in &archive.new_blocks {
+            insert_block(
+                self.conn,
+                *block_id,
+                digest,
+                *length,
+                archive.archive_id,
+                *archive_off,
+            )?;
+        }
 
-        update.blocks.write_all(block)?;
+        if let Some(pool_name) = &archive.pool_name {
+            insert_index(self.conn, pool_name, b2_file_id, b2_length, &archive.index)?;
 
-        update.archive_len += length;
-        if update.archive_len < config.min_archive_len {
-            return Ok(());
+            self.new_pool_archives.insert(pool_name.clone());
+        }
+
+        self.finished_archives += 1;
+
+        Ok(())
+    }
+
+    fn maybe_checkpoint(
+        &mut self,
+        config: &Config,
+        client: &Client,
+        lock: &Lock,
+    ) -> Fallible<Option<(i64, Vec<u8>)>> {
+        let due = (config.checkpoint_archives != 0
+            && self.finished_archives >= config.checkpoint_archives)
+            || (config.checkpoint_minutes != 0
+                && self.last_checkpoint.elapsed()
+                    >= Duration::from_secs(config.checkpoint_minutes * 60));
+
+        if !due {
+            return Ok(None);
+        }
+
+        self.checkpoint(config, client, lock)
+    }
+
+    fn checkpoint(
+        &mut self,
+        config: &Config,
+        client: &Client,
+        lock: &Lock,
+    ) -> Fallible<Option<(i64, Vec<u8>)>> {
+        if let Some(pool) = client.pool() {
+            if !self.new_pool_archives.is_empty() {
+                upload_refs(
+                    &pool,
+                    lock,
+                    config,
+                    &select_referenced_pool_archives(self.conn)?,
+                )?;
+                verify_pool_archives(&pool, &self.new_pool_archives)?;
+
+                self.new_pool_archives.clear();
+            }
         }
 
-        let next_archive_id = insert_def_archive(update.conn)?;
+        self.finished_archives = 0;
+        self.last_checkpoint = Instant::now();
+
+        let mut patchset = Vec::new();
+        self.session.patchset_strm(&mut patchset)?;
+
+        if patchset.is_empty() {
+            return Ok(None);
+        }
+
+        // The patchset is kept in the manifest until it is uploaded without holding the update.
+        let patchset_id = insert_pending_patchset(self.conn, &patchset)?;
+
+        lock.check()?;
+        self.trans.take().unwrap().commit()?;
+
+        self.trans = Some(Transaction::new_unchecked(
+            self.conn,
+            TransactionBehavior::Exclusive,
+        )?);
+        self.session = start_session(self.conn)?;
+
+        Ok(Some((patchset_id, patchset)))
+    }
+
+    fn commit(&mut self, client: &Client, lock: &Lock) -> Fallible<bool> {
+        let mut patchset = Vec::new();
+        self.session.patchset_strm(&mut patchset)?;
+
+        let changed = !patchset.is_empty();
+
+        if changed {
+            self.session.set_enabled(false);
+
+            upload_patchset(self.conn, client, lock, patchset.as_slice())?;
+        }
+
+        lock.check()?;
+        self.trans.take().unwrap().commit()?;
+
+        Ok(changed)
+    }
+
+    pub fn unchanged_file(&self, path: &Path, metadata: &Metadata) -> Fallible<bool> {
+        if let Some(file_id) = select_unchanged_file(self.conn, path, metadata)? {
+            insert_visited_file(self.conn, file_id)?;
+
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    pub fn retain(&self, path: &Path) -> Fallible {
+        insert_visited_objects_by_path(self.conn, path)
+    }
+
+    pub fn open_file(&self, path: &Path, metadata: &Metadata, xattrs: &Xattrs) -> Fallible<i64> {
+        let new_file_id = insert_new_file(self.conn, path, metadata, None)?;
+
+        for (name, value) in xattrs {
+            let xattr_id = insert_xattr(self.conn, name, value)?;
+
+            insert_new_file_xattr(self.conn, new_file_id, xattr_id)?;
+        }
+
+        Ok(new_file_id)
+    }
+
+    pub fn hard_link(
+        &self,
+        path: &Path,
+        metadata: &Metadata,
+        xattrs: &Xattrs,
+        target: &Path,
+    ) -> Fallible {
+        let new_file_id = insert_new_file(self.conn, path, metadata, Some(target))?;
+
+        for (name, value) in xattrs {
+            let xattr_id = insert_xattr(self.conn, name, value)?;
+
+            insert_new_file_xattr(self.conn, new_file_id, xattr_id)?;
+        }
+
+        update_new_file(self.conn, new_file_id)
+    }
+
+    pub fn close_file(&self, new_file_id: i64, mappings: &[(u64, i64)]) -> Fallible {
+        for (offset, block_id) in mappings {
+            insert_new_mapping(self.conn, new_file_id, *offset, *block_id)?;
+        }
+
+        update_new_file(self.conn, new_file_id)
+    }
+
+    pub fn discard_file(&self, new_file_id: i64) -> Fallible {
+        delete_new_file(self.conn, new_file_id)
+    }
+
+    pub fn directory(&self, path: &Path, metadata: &Metadata, xattrs: &Xattrs) -> Fallible {
+        let dir_id = if let Some(dir_id) = select_unchanged_directory(self.conn, path, metadata)? {
+            dir_id
+        } else {
+            update_directory(self.conn, path, self.snapshot_id)?;
+
+            let dir_id = insert_directory(self.conn, path, metadata, self.snapshot_id)?;
+
+            for (name, value) in xattrs {
+                let xattr_id = insert_xattr(self.conn, name, value)?;
+
+                insert_directory_xattr(self.conn, dir_id, xattr_id)?;
+            }
+
+            dir_id
+        };
+
+        insert_visited_directory(self.conn, dir_id)?;
+
+        Ok(())
+    }
+
+    pub fn symlink(
+        &self,
+        path: &Path,
+        target: &Path,
+        metadata: &Metadata,
+        xattrs: &Xattrs,
+    ) -> Fallible {
+        let symlink_id = if let Some(symlink_id) =
+            select_unchanged_symbolic_link(self.conn, path, target, metadata)?
+        {
+            symlink_id
+        } else {
+            update_symbolic_link(self.conn, path, self.snapshot_id)?;
+
+            let symlink_id =
+                insert_symbolic_link(self.conn, path, target, metadata, self.snapshot_id)?;
+
+            for (name, value) in xattrs {
+                let xattr_id = insert_xattr(self.conn, name, value)?;
+
+                insert_symbolic_link_xattr(self.conn, symlink_id, xattr_id)?;
+            }
+
+            symlink_id
+        };
+
+        insert_visited_symbolic_link(self.conn, symlink_id)?;
+
+        Ok(())
+    }
+
+    pub fn special_file(&self, path: &Path, metadata: &Metadata) -> Fallible {
+        let special_file_id = if let Some(special_file_id) =
+            select_unchanged_special_file(self.conn, path, metadata)?
+        {
+            special_file_id
+        } else {
+            update_special_file(self.conn, path, self.snapshot_id)?;
+
+            insert_special_file(self.conn, path, metadata, self.snapshot_id)?
+        };
+
+        insert_visited_special_file(self.conn, special_file_id)?;
+
+        Ok(())
+    }
+}
 
-        archive_id = replace(&mut update.archive_id, next_archive_id);
-        archive_len = replace(&mut update.archive_len, 0);
-        blocks = replace(&mut update.blocks, tempfile()?);
+#[derive(Clone, Copy)]
+pub enum RestoreOwner {
+    ByName,
+    Numeric,
+    Skip,
+}
+
+fn restore_owner(path: &Path, attrs: &Attributes, owner: RestoreOwner) -> Fallible {
+    let (uid, gid) = match owner {
+        RestoreOwner::Skip => return Ok(()),
+        RestoreOwner::Numeric => (attrs.uid, attrs.gid),
+        RestoreOwner::ByName => (
+            attrs
+                .user_name
+                .as_deref()
+                .and_then(lookup_user)
+                .or(attrs.uid),
+            attrs
+                .group_name
+                .as_deref()
+                .and_then(lookup_group)
+                .or(attrs.gid),
+        ),
     };
 
-    let name = format!("archive_{archive_id}");
-    blocks.rewind()?;
-    let (b2_file_id, b2_length) = client.upload(&name, &mut blocks)?;
+    if uid.is_some() || gid.is_some() {
+        lchown(path, uid, gid)?;
+    }
+
+    Ok(())
+}
+
+fn restore_times(path: &Path, attrs: &Attributes) -> Fallible {
+    fn timespec(time: i64) -> TimeSpec {
+        TimeSpec::new(
+            time.div_euclid(1_000_000_000),
+            time.rem_euclid(1_000_000_000),
+        )
+    }
+
+    if let Some((atime, mtime)) = attrs.times {
+        utimensat(
+            AT_FDCWD,
+            path,
+            &timespec(atime),
+            &timespec(mtime),
+            UtimensatFlags::NoFollowSymlink,
+        )?;
+    }
+
+    Ok(())
+}
+
+fn lookup_user(name: &str) -> Option<u32> {
+    thread_local! {
+        static USERS: RefCell<HashMap<String, Option<u32>>> = RefCell::default();
+    }
+
+    USERS.with_borrow_mut(|users| {
+        *users.entry(name.to_owned()).or_insert_with(|| {
+            User::from_name(name)
+                .ok()
+                .flatten()
+                .map(|user| user.uid.as_raw())
+        })
+    })
+}
+
+fn lookup_group(name: &str) -> Option<u32> {
+    thread_local! {
+        static GROUPS: RefCell<HashMap<String, Option<u32>>> = RefCell::default();
+    }
+
+    GROUPS.with_borrow_mut(|groups| {
+        *groups.entry(name.to_owned()).or_insert_with(|| {
+            Group::from_name(name)
+                .ok()
+                .flatten()
+                .map(|group| group.gid.as_raw())
+        })
+    })
+}
+
+fn collect_closed_new_files(conn: &Connection, snapshot_id: i64) -> Fallible {
+    select_closed_new_files(conn, |new_file_id, path| {
+        update_file(conn, path, snapshot_id)?;
+
+        let file_id = insert_file(conn, new_file_id, snapshot_id)?;
+
+        insert_mappings(conn, file_id, new_file_id)?;
+        insert_file_xattrs(conn, file_id, new_file_id)?;
+        insert_visited_file(conn, file_id)?;
+
+        delete_new_file(conn, new_file_id)?;
+
+        Ok(())
+    })?;
+
+    select_closed_new_hard_links(conn, |new_file_id, path, target| {
+        if let Some(target_id) = select_hard_link_target(conn, new_file_id)? {
+            update_file(conn, path, snapshot_id)?;
+
+            let file_id = insert_file(conn, new_file_id, snapshot_id)?;
+
+            insert_hard_link_mappings(conn, file_id, target_id)?;
+            insert_file_xattrs(conn, file_id, new_file_id)?;
+            insert_visited_file(conn, file_id)?;
+        } else {
+            println!(
+                "Keeping previous version of {} as its hard link target {} was not backed up",
+                path.display(),
+                target.display()
+            );
+
+            insert_visited_objects_by_path(conn, path)?;
+        }
+
+        delete_new_file(conn, new_file_id)?;
+
+        Ok(())
+    })
+}
+
+pub fn store_block(
+    update: &Mutex<Update>,
+    index: &BlockIndex,
+    config: &Config,
+    compression_level: i32,
+    block: &[u8],
+) -> Fallible<i64> {
+    let digest = *hash(block).as_bytes();
+
+    let (block_id, new) = index.get_or_reserve(&digest);
+
+    if !new {
+        return Ok(block_id);
+    }
+
+    let length = block.len().try_into().unwrap();
+
+    if config.pool.is_some() {
+        let mut update = update.lock().unwrap();
+        let conn = update.conn;
+
+        if let Some((pool_name, archive_off)) = select_pool_block(conn, &digest)? {
+            let archive_id = match select_pool_archive(conn, &pool_name)? {
+                Some(archive_id) => archive_id,
+                None => {
+                    let archive_id = insert_pool_archive_reference(conn, &pool_name)?;
+                    update.new_pool_archives.insert(pool_name);
+                    archive_id
+                }
+            };
+
+            insert_block(conn, block_id, &digest, length, archive_id, archive_off)?;
+
+            return Ok(block_id);
+        }
+    }
+
+    let archive = {
+        let mut archives = index.worker_archives().lock().unwrap();
+
+        let archive = match archives.entry(compression_level) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(new_archive(update.lock().unwrap().conn, config)?),
+        };
+
+        archive.blocks.write_all(block)?;
+        push_index_record(&mut archive.index, &digest, length, archive.archive_len);
+        archive
+            .new_blocks
+            .push((block_id, digest, length, archive.archive_len));
+
+        archive.archive_len += length;
+        if archive.archive_len < config.min_archive_len {
+            return Ok(block_id);
+        }
+
+        archives.remove(&compression_level).unwrap()
+    };
+
+    queue_archive(update, compression_level, archive)?;
+
+    Ok(block_id)
+}
+
+impl Archive {
+    fn append(&mut self, mut other: Archive) -> Fallible {
+        other.blocks.rewind()?;
+        copy(&mut other.blocks, &mut self.blocks)?;
+
+        for (block_id, digest, length, archive_off) in other.new_blocks {
+            let archive_off = self.archive_len + archive_off;
+
+            push_index_record(&mut self.index, &digest, length, archive_off);
+            self.new_blocks
+                .push((block_id, digest, length, archive_off));
+        }
+
+        self.archive_len += other.archive_len;
+
+        Ok(())
+    }
+}
+
+fn new_archive(conn: &Connection, config: &Config) -> Fallible<Archive> {
+    let pool_name = match config.pool {
+        Some(_) => Some(pool_archive_name()?),
+        None => None,
+    };
+
+    Ok(Archive {
+        archive_id: insert_def_archive(conn, pool_name.as_deref())?,
+        pool_name,
+        archive_len: 0,
+        blocks: tempfile()?,
+        index: Vec::new(),
+        new_blocks: Vec::new(),
+    })
+}
 
-    let update = update.lock().unwrap();
+fn queue_archive(update: &Mutex<Update>, compression_level: i32, archive: Archive) -> Fallible {
+    let uploads = update.lock().unwrap().uploads.clone().unwrap();
 
-    update_archive(update.conn, archive_id, archive_len, &b2_file_id, b2_length)?;
-    collect_closed_new_files(update.conn)?;
+    uploads
+        .send((compression_level, archive))
+        .map_err(|_| "Failed to queue archive for upload")?;
 
     Ok(())
 }
 
-fn upload_patchset(conn: &Connection, client: &Client, patchset: impl Read) -> Fallible {
+fn finish_archives(update: &Mutex<Update>) -> Fallible {
+    let (archives, uploads) = {
+        let mut update = update.lock().unwrap();
+
+        let mut archives = Vec::new();
+
+        for (compression_level, archive) in take(&mut update.archives) {
+            if archive.archive_len != 0 {
+                archives.push((compression_level, archive));
+            } else {
+                delete_archive(update.conn, archive.archive_id)?;
+            }
+        }
+
+        (archives, update.uploads.clone().unwrap())
+    };
+
+    for archive in archives {
+        uploads
+            .send(archive)
+            .map_err(|_| "Failed to queue archive for upload")?;
+    }
+
+    Ok(())
+}
+
+fn upload_archives(
+    update: &Mutex<Update>,
+    config: &Config,
+    client: &Client,
+    lock: &Lock,
+    receiver: &Mutex<Option<Receiver<(i32, Archive)>>>,
+) -> Fallible {
+    loop {
+        let (compression_level, mut archive) = match &*receiver.lock().unwrap() {
+            Some(receiver) => match receiver.recv() {
+                Ok(archive) => archive,
+                Err(_) => return Ok(()),
+            },
+            None => return Ok(()),
+        };
+
+        let res = upload_archive(client, lock, compression_level, &mut archive)
+            .and_then(|(b2_file_id, b2_length)| {
+                let mut update = update.lock().unwrap();
+
+                update.finish_archive(&archive, &b2_file_id, b2_length)?;
+                collect_closed_new_files(update.conn, update.snapshot_id)?;
+                update.maybe_checkpoint(config, client, lock)
+            })
+            .and_then(|checkpoint| match checkpoint {
+                Some(checkpoint) => upload_checkpoint(update, client, lock, checkpoint),
+                None => Ok(()),
+            });
+
+        if res.is_err() {
+            // Dropping the receiver makes further uploads fail instead of blocking.
+            receiver.lock().unwrap().take();
+
+            return res;
+        }
+    }
+}
+
+fn upload_checkpoint(
+    update: &Mutex<Update>,
+    client: &Client,
+    lock: &Lock,
+    (patchset_id, patchset): (i64, Vec<u8>),
+) -> Fallible {
+    let name = format!("manifest_{patchset_id}");
+
+    lock.check()?;
+    let (b2_file_id, b2_length) = client.upload(&name, patchset.as_slice())?;
+
+    update_patchset(
+        update.lock().unwrap().conn,
+        patchset_id,
+        &b2_file_id,
+        b2_length,
+    )?;
+
+    println!("Recorded checkpoint");
+
+    Ok(())
+}
+
+fn upload_archive(
+    client: &Client,
+    lock: &Lock,
+    compression_level: i32,
+    archive: &mut Archive,
+) -> Fallible<(String, u64)> {
+    archive.blocks.rewind()?;
+
+    match (client.pool(), &archive.pool_name) {
+        (Some(pool), Some(name)) => upload_pool_archive(
+            &pool,
+            lock,
+            name,
+            compression_level,
+            &mut archive.blocks,
+            &archive.index,
+        ),
+        _ => {
+            let name = format!("archive_{}", archive.archive_id);
+
+            lock.check()?;
+            client.upload_with_level(&name, compression_level, &mut archive.blocks)
+        }
+    }
+}
+
+fn download_archive(conn: &Connection, client: &Client, archive_id: i64) -> Fallible<File> {
+    let mut archive = tempfile()?;
+
+    match select_archive_pool_name(conn, archive_id)? {
+        Some(pool_name) => {
+            let pool = client
+                .pool()
+                .ok_or("Manifest references a block pool which is not configured")?;
+
+            copy(&mut pool.download(&pool_name)?, &mut archive)?;
+        }
+        None => {
+            let name = format!("archive_{archive_id}");
+
+            copy(&mut client.download(&name)?, &mut archive)?;
+        }
+    }
+
+    Ok(archive)
+}
+
+fn check_archive(
+    conn: &Connection,
+    client: &Client,
+    archive_id: i64,
+    buffer: &mut Vec<u8>,
+) -> Fallible<usize> {
+    let mut corrupted_blocks = 0;
+
+    let name = format!("archive_{archive_id}");
+    let archive = download_archive(conn, client, archive_id)?;
+
+    let blocks = select_blocks_by_archive(conn, archive_id)?;
+
+    for (block_id, stored_digest, length, archive_off) in blocks {
+        buffer.resize(length as _, 0);
+        archive.read_exact_at(buffer, archive_off)?;
+
+        let digest = hash(buffer);
+        if digest != stored_digest {
+            println!(
+                "Block {} in {} has digest {}, but should have {}.",
+                block_id,
+                name,
+                digest.to_hex(),
+                hex::encode(stored_digest),
+            );
+            corrupted_blocks += 1;
+        }
+    }
+
+    Ok(corrupted_blocks)
+}
+
+fn start_session(conn: &Connection) -> Fallible<Session<'_>> {
+    let mut session = Session::new(conn)?;
+    session.table_filter(Some(|name: &str| {
+        !name.starts_with("pool_") && name != "patchsets"
+    }));
+    session.attach::<&CStr>(None)?;
+
+    Ok(session)
+}
+
+fn upload_patchset(
+    conn: &Connection,
+    client: &Client,
+    lock: &Lock,
+    patchset: impl Read,
+) -> Fallible {
     let patchset_id = insert_def_patchset(conn)?;
 
     let name = format!("manifest_{patchset_id}");
+
+    lock.check()?;
     let (b2_file_id, b2_length) = client.upload(&name, patchset)?;
 
     update_patchset(conn, patchset_id, &b2_file_id, b2_length)?;
@@ -626,6 +1720,41 @@ fn upload_patchset(conn: &Connection, client: &Client, patchset: impl Read) -> F
     Ok(())
 }
 
+fn upload_pending_patchsets(conn: &Connection, client: &Client, lock: &Lock) -> Fallible {
+    let pending_patchsets = select_pending_patchsets(conn)?;
+
+    if pending_patchsets.is_empty() {
+        return Ok(());
+    }
+
+    let mut uploaded = client
+        .list("manifest_")?
+        .into_iter()
+        .map(|(name, b2_file_id, b2_length)| (name, (b2_file_id, b2_length)))
+        .collect::<HashMap<_, _>>();
+
+    for (patchset_id, patchset) in &pending_patchsets {
+        let name = format!("manifest_{patchset_id}");
+
+        let (b2_file_id, b2_length) = match uploaded.remove(&name) {
+            Some(object) => object,
+            None => {
+                lock.check()?;
+                client.upload(&name, patchset.as_slice())?
+            }
+        };
+
+        update_patchset(conn, *patchset_id, &b2_file_id, b2_length)?;
+    }
+
+    println!(
+        "Recorded {} patchsets left pending by an interrupted backup",
+        pending_patchsets.len()
+    );
+
+    Ok(())
+}
+
 fn apply_patchset(
     conn: &Connection,
     mut patchset: impl Read,
@@ -647,25 +1776,119 @@ fn apply_patchset(
     insert_patchset(conn, patchset_id, b2_file_id, b2_length)
 }
 
+fn resolve_snapshot(conn: &Connection, snapshot: Option<&str>) -> Fallible<Option<i64>> {
+    let snapshot = match snapshot {
+        Some(snapshot) => snapshot,
+        None => return Ok(None),
+    };
+
+    let snapshot_id = if snapshot == "latest" {
+        select_latest_snapshot(conn)?
+    } else if let Ok(snapshot_id) = snapshot.parse() {
+        select_snapshot(conn, snapshot_id)?.then_some(snapshot_id)
+    } else {
+        select_snapshot_by_time(conn, snapshot)?
+    };
+
+    let snapshot_id = snapshot_id.ok_or_else(|| format!("No snapshot found for {snapshot}"))?;
+
+    Ok(Some(snapshot_id))
+}
+
+fn delete_unvisited_objects(conn: &Connection, snapshot_id: i64, paths: &[&Path]) -> Fallible {
+    let mut deleted_files = 0;
+    let mut deleted_dirs = 0;
+    let mut deleted_symlinks = 0;
+    let mut deleted_special_files = 0;
+
+    for path in paths {
+        deleted_files += update_unvisited_files(conn, snapshot_id, path)?;
+        deleted_dirs += update_unvisited_directories(conn, snapshot_id, path)?;
+        deleted_symlinks += update_unvisited_symbolic_links(conn, snapshot_id, path)?;
+        deleted_special_files += update_unvisited_special_files(conn, snapshot_id, path)?;
+    }
+
+    println!(
+        "Deleted {deleted_files} unvisited files, {deleted_dirs} unvisted directories, {deleted_symlinks} unvisited symbolic links and {deleted_special_files} unvisited special files"
+    );
+
+    Ok(())
+}
+
+fn delete_pending_archives(conn: &Connection, client: &Client, lock: &Lock) -> Fallible<bool> {
+    let pending_archives = select_pending_archives(conn)?;
+
+    if pending_archives.is_empty() {
+        return Ok(false);
+    }
+
+    let uploaded = client
+        .list("archive_")?
+        .into_iter()
+        .map(|(name, b2_file_id, _)| (name, b2_file_id))
+        .collect::<HashMap<_, _>>();
+
+    let mut pending_pool_archives = Vec::new();
+
+    for (archive_id, pool_name) in &pending_archives {
+        match pool_name {
+            Some(pool_name) => pending_pool_archives.push(pool_name.clone()),
+            None => {
+                let name = format!("archive_{archive_id}");
+
+                if let Some(b2_file_id) = uploaded.get(&name) {
+                    lock.check()?;
+                    client.remove(&name, b2_file_id)?;
+                }
+            }
+        }
+
+        delete_archive(conn, *archive_id)?;
+    }
+
+    if !pending_pool_archives.is_empty() {
+        match client.pool() {
+            Some(pool) => remove_pending_pool_archives(&pool, lock, &pending_pool_archives)?,
+            None => println!(
+                "Keeping {} pending pool archives as no block pool is configured",
+                pending_pool_archives.len()
+            ),
+        }
+    }
+
+    println!(
+        "Deleted {} archives left pending by an interrupted backup",
+        pending_archives.len()
+    );
+
+    Ok(true)
+}
+
 fn delete_unused_archives(
     conn: &Connection,
-    keep_unvisited_files: bool,
-) -> Fallible<Vec<(i64, String)>> {
-    if !keep_unvisited_files {
-        let deleted_files = delete_unvisited_files(conn)?;
-        let deleted_dirs = delete_unvisited_directories(conn)?;
-        let deleted_symlinks = delete_unvisited_symbolic_links(conn)?;
-        println!(
-            "Deleted {deleted_files} unvisited files, {deleted_dirs} unvisted directories and {deleted_symlinks} unvisited symbolic links"
-        );
+    keep_snapshots: usize,
+) -> Fallible<Vec<(i64, String, Option<String>)>> {
+    if keep_snapshots != 0 {
+        let expired_snapshots = delete_expired_snapshots(conn, keep_snapshots)?;
+        println!("Expired {expired_snapshots} snapshots");
     }
 
+    let retired_files = delete_retired_files(conn)?;
+    let retired_dirs = delete_retired_directories(conn)?;
+    let retired_symlinks = delete_retired_symbolic_links(conn)?;
+    let retired_special_files = delete_retired_special_files(conn)?;
+    println!(
+        "Dropped {retired_files} files, {retired_dirs} directories, {retired_symlinks} symbolic links and {retired_special_files} special files no longer part of any snapshot"
+    );
+
+    delete_unused_xattrs(conn)?;
+
     let deleted_blocks = delete_unused_blocks(conn)?;
     println!("Deleted {deleted_blocks} unmapped blocks");
 
     let unused_archives = select_unused_archives(conn)?;
 
-    for (archive_id, _) in &unused_archives {
+    for (archive_id, _, _) in &unused_archives {
         delete_archive(conn, *archive_id)?;
     }
 
@@ -673,3 +1896,161 @@ fn delete_unused_archives(
 
     Ok(unused_archives)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::{hard_link, metadata, write};
+
+    use serde_yaml::from_str;
+    use tempfile::tempdir;
+
+    use crate::{
+        database::{
+            insert_block, insert_def_archive, insert_new_file, insert_new_mapping,
+            select_next_snapshot, update_archive, update_new_file,
+        },
+        lock::with_lock,
+    };
+
+    fn recorded_files(conn: &Connection) -> Vec<(String, i64)> {
+        let mut stmt = conn
+            .prepare("SELECT CAST(files.path AS TEXT), COUNT(mappings.block_id) FROM files LEFT JOIN mappings ON mappings.file_id = files.id GROUP BY files.id ORDER BY files.path")
+            .unwrap();
+
+        stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn hard_links_wait_for_their_target() {
+        let dir = tempdir().unwrap();
+        let conn = open_connection(&dir.path().join("manifest.db")).unwrap();
+
+        let target = dir.path().join("target");
+        let link = dir.path().join("link");
+        write(&target, b"data").unwrap();
+        hard_link(&target, &link).unwrap();
+        let metadata = metadata(&target).unwrap();
+
+        let snapshot_id = select_next_snapshot(&conn).unwrap();
+
+        let target_id = insert_new_file(&conn, &target, &metadata, None).unwrap();
+
+        let link_id = insert_new_file(&conn, &link, &metadata, Some(&target)).unwrap();
+        update_new_file(&conn, link_id).unwrap();
+
+        // The link is closed while its target is still being read.
+        collect_closed_new_files(&conn, snapshot_id).unwrap();
+        assert!(recorded_files(&conn).is_empty());
+
+        let archive_id = insert_def_archive(&conn, None).unwrap();
+        update_archive(&conn, archive_id, 4, "archive", 4, None).unwrap();
+        insert_block(&conn, 1, hash(b"data").as_bytes(), 4, archive_id, 0).unwrap();
+        insert_new_mapping(&conn, target_id, 0, 1).unwrap();
+        update_new_file(&conn, target_id).unwrap();
+
+        collect_closed_new_files(&conn, snapshot_id).unwrap();
+        assert_eq!(
+            recorded_files(&conn),
+            [
+                (link.to_str().unwrap().to_owned(), 1),
+                (target.to_str().unwrap().to_owned(), 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn pending_patchsets_are_uploaded() {
+        let dir = tempdir().unwrap();
+        let store = tempdir().unwrap();
+        let conn = open_connection(&dir.path().join("manifest.db")).unwrap();
+
+        let config: Config = from_str(&format!(
+            r#"
+backend:
+  type: local
+  path: {}
+key: '0000000000000000000000000000000000000000000000000000000000000000'
+"#,
+            store.path().display()
+        ))
+        .unwrap();
+        let client = Client::new(&config).unwrap();
+
+        let uploaded_id = insert_pending_patchset(&conn, b"uploaded").unwrap();
+        let pending_id = insert_pending_patchset(&conn, b"pending").unwrap();
+
+        // The backup was interrupted after uploading the first patchset.
+        client
+            .upload(&format!("manifest_{uploaded_id}"), &b"uploaded"[..])
+            .unwrap();
+
+        with_lock(&client, &config, |lock| {
+            upload_pending_patchsets(&conn, &client, lock)
+        })
+        .unwrap();
+
+        assert!(select_pending_patchsets(&conn).unwrap().is_empty());
+        assert_eq!(select_patchset_lengths(&conn).unwrap().len(), 2);
+
+        let mut patchset = String::new();
+        client
+            .download(&format!("manifest_{pending_id}"))
+            .unwrap()
+            .read_to_string(&mut patchset)
+            .unwrap();
+        assert_eq!(patchset, "pending");
+    }
+
+    #[test]
+    fn appended_archives_keep_their_blocks() {
+        let dir = tempdir().unwrap();
+        let conn = open_connection(&dir.path().join("manifest.db")).unwrap();
+
+        let config: Config = from_str(
+            r#"
+backend:
+  type: local
+  path: /nonexistent
+key: '0000000000000000000000000000000000000000000000000000000000000000'
+"#,
+        )
+        .unwrap();
+
+        let mut archives = Vec::new();
+
+        for (block_id, data) in [(1, &b"first"[..]), (2, &b"second"[..])] {
+            let mut archive = new_archive(&conn, &config).unwrap();
+            let length = data.len() as u64;
+
+            archive.blocks.write_all(data).unwrap();
+            push_index_record(&mut archive.index, hash(data).as_bytes(), length, 0);
+            archive
+                .new_blocks
+                .push((block_id, *hash(data).as_bytes(), length, 0));
+            archive.archive_len = length;
+
+            archives.push(archive);
+        }
+
+        let other = archives.pop().unwrap();
+        let mut archive = archives.pop().unwrap();
+        archive.append(other).unwrap();
+
+        assert_eq!(archive.archive_len, 11);
+        assert_eq!(archive.index.len(), 2 * (DIGEST_LEN + 2 * 8));
+
+        for (_, digest, length, archive_off) in &archive.new_blocks {
+            let mut buffer = vec![0; *length as usize];
+            archive
+                .blocks
+                .read_exact_at(&mut buffer, *archive_off)
+                .unwrap();
+            assert_eq!(hash(&buffer).as_bytes(), digest);
+        }
+    }
+}
@@ -76,3 +76,13 @@ pub fn unpack(key: &Key, name: &str, mut buf: Vec<u8>) -> Fallible<impl Read> {
 
     Ok(reader)
 }
+
+pub fn random_id() -> Fallible<String> {
+    let mut id = [0; 16];
+
+    SystemRandom::new()
+        .fill(&mut id)
+        .map_err(|_| "Failed to generate random identifier")?;
+
+    Ok(hex::encode(id))
+}
@@ -0,0 +1,472 @@
+/*
+Copyright 2019 Adam Reichold
+
+This file is part of b2_backup.
+
+b2_backup is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+b2_backup is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+
+use blake3::OUT_LEN as DIGEST_LEN;
+use rusqlite::Connection;
+use serde::Deserialize;
+
+use super::{
+    client::Client,
+    database::{
+        delete_pool_archive, insert_pool_archive, insert_pool_block, select_pool_archive_names,
+    },
+    lock::Lock,
+    pack::random_id,
+    Config, Fallible,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct PoolConfig {
+    pub prefix: String,
+}
+
+pub fn push_index_record(index: &mut Vec<u8>, digest: &[u8], length: u64, archive_off: u64) {
+    index.extend_from_slice(digest);
+    index.extend_from_slice(&length.to_le_bytes());
+    index.extend_from_slice(&archive_off.to_le_bytes());
+}
+
+pub fn insert_index(
+    conn: &Connection,
+    name: &str,
+    b2_file_id: &str,
+    b2_length: u64,
+    index: &[u8],
+) -> Fallible {
+    if !index.len().is_multiple_of(RECORD_LEN) {
+        return Err(format!("Index of pool archive {name} is malformed").into());
+    }
+
+    let records = index.chunks_exact(RECORD_LEN).map(|record| {
+        let (digest, record) = record.split_at(DIGEST_LEN);
+        let (length, archive_off) = record.split_at(8);
+
+        (
+            digest,
+            u64::from_le_bytes(length.try_into().unwrap()),
+            u64::from_le_bytes(archive_off.try_into().unwrap()),
+        )
+    });
+
+    let length = records
+        .clone()
+        .map(|(_digest, length, archive_off)| archive_off + length)
+        .max()
+        .unwrap_or(0);
+
+    insert_pool_archive(conn, name, length, b2_file_id, b2_length)?;
+
+    for (digest, _length, archive_off) in records {
+        insert_pool_block(conn, digest, name, archive_off)?;
+    }
+
+    Ok(())
+}
+
+pub fn sync_pool(conn: &Connection, pool: &Client) -> Fallible {
+    let archives = pool
+        .list("archive_")?
+        .into_iter()
+        .map(|(name, b2_file_id, b2_length)| (name, (b2_file_id, b2_length)))
+        .collect::<HashMap<_, _>>();
+
+    let mut known_archives = select_pool_archive_names(conn)?
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+    let mut new_archives = 0;
+
+    for (index_name, _, _) in pool.list("index_")? {
+        let name = archive_name(&index_name);
+
+        if known_archives.remove(&name) {
+            continue;
+        }
+
+        let (b2_file_id, b2_length) = match archives.get(&name) {
+            Some(archive) => archive,
+            None => continue,
+        };
+
+        let mut index = Vec::new();
+        pool.download(&index_name)?.read_to_end(&mut index)?;
+
+        insert_index(conn, &name, b2_file_id, *b2_length, &index)?;
+
+        new_archives += 1;
+    }
+
+    for name in &known_archives {
+        delete_pool_archive(conn, name)?;
+    }
+
+    println!(
+        "Found {} new and {} removed archives in block pool",
+        new_archives,
+        known_archives.len()
+    );
+
+    Ok(())
+}
+
+pub fn pool_archive_name() -> Fallible<String> {
+    Ok(format!("archive_{}", random_id()?))
+}
+
+pub fn upload_pool_archive(
+    pool: &Client,
+    lock: &Lock,
+    name: &str,
+    compression_level: i32,
+    reader: impl Read,
+    index: &[u8],
+) -> Fallible<(String, u64)> {
+    lock.check()?;
+    let (b2_file_id, b2_length) = pool.upload_with_level(name, compression_level, reader)?;
+
+    lock.check()?;
+    pool.upload(&index_name(name), index)?;
+
+    Ok((b2_file_id, b2_length))
+}
+
+pub fn upload_refs(pool: &Client, lock: &Lock, config: &Config, names: &[String]) -> Fallible {
+    let prefix = format!("refs/{}_", hex::encode(&config.prefix));
+
+    let old_refs = pool.list(&prefix)?;
+
+    lock.check()?;
+    pool.upload(
+        &format!("{}{}", prefix, random_id()?),
+        names.join("\n").as_bytes(),
+    )?;
+
+    for (name, b2_file_id, _) in old_refs {
+        lock.check()?;
+        pool.remove(&name, &b2_file_id)?;
+    }
+
+    Ok(())
+}
+
+pub fn verify_pool_archives(pool: &Client, names: &HashSet<String>) -> Fallible {
+    let indexes = pool
+        .list("index_")?
+        .into_iter()
+        .map(|(index_name, _, _)| archive_name(&index_name))
+        .collect::<HashSet<_>>();
+
+    for name in names {
+        if !indexes.contains(name) {
+            return Err(format!(
+                "Pool archive {name} was removed concurrently, please retry the backup"
+            )
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+pub fn remove_pool_archives(
+    pool: &Client,
+    lock: &Lock,
+    config: &Config,
+    refs: &[String],
+    unused_archives: Vec<(String, String)>,
+) -> Fallible {
+    upload_refs(pool, lock, config, refs)?;
+
+    for (name, _) in &unused_archives {
+        let index_name = index_name(name);
+
+        for (found_name, b2_file_id, _) in pool.list(&index_name)? {
+            if found_name == index_name {
+                lock.check()?;
+                pool.remove(&found_name, &b2_file_id)?;
+            }
+        }
+    }
+
+    let referenced_archives = match read_refs(pool) {
+        Ok(referenced_archives) => referenced_archives,
+        Err(err) => {
+            println!(
+                "Keeping {} pool archives as references could not be read: {}",
+                unused_archives.len(),
+                err
+            );
+            return Ok(());
+        }
+    };
+
+    for (name, b2_file_id) in unused_archives {
+        if referenced_archives.contains(&name) {
+            println!("Keeping pool archive {name} as it is still referenced by other hosts");
+            continue;
+        }
+
+        lock.check()?;
+        if let Err(err) = pool.remove(&name, &b2_file_id) {
+            println!("Failed to remove pool archive {name}: {err}");
+        }
+    }
+
+    Ok(())
+}
+
+pub fn remove_pending_pool_archives(pool: &Client, lock: &Lock, names: &[String]) -> Fallible {
+    let objects = pool
+        .list("index_")?
+        .into_iter()
+        .chain(pool.list("archive_")?)
+        .map(|(name, b2_file_id, _)| (name, b2_file_id))
+        .collect::<HashMap<_, _>>();
+
+    for name in names {
+        // The index goes first so that other hosts stop using the archive.
+        for name in [index_name(name), name.clone()] {
+            if let Some(b2_file_id) = objects.get(&name) {
+                lock.check()?;
+                pool.remove(&name, b2_file_id)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn read_refs(pool: &Client) -> Fallible<HashSet<String>> {
+    let mut referenced_archives = HashSet::new();
+
+    for (name, _, _) in pool.list("refs/")? {
+        let mut refs = String::new();
+        pool.download(&name)?.read_to_string(&mut refs)?;
+
+        referenced_archives.extend(
+            refs.split('\n')
+                .filter(|name| !name.is_empty())
+                .map(str::to_owned),
+        );
+    }
+
+    Ok(referenced_archives)
+}
+
+fn index_name(name: &str) -> String {
+    format!("index_{}", name.trim_start_matches("archive_"))
+}
+
+fn archive_name(index_name: &str) -> String {
+    format!("archive_{}", index_name.trim_start_matches("index_"))
+}
+
+const RECORD_LEN: usize = DIGEST_LEN + 2 * 8;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::slice::from_ref;
+
+    use serde_yaml::from_str;
+    use tempfile::{tempdir, TempDir};
+
+    use crate::{
+        database::{open_connection, select_pool_block},
+        lock::with_lock,
+    };
+
+    fn config(store: &TempDir, host: &str) -> Config {
+        from_str(&format!(
+            r#"
+backend:
+  type: local
+  path: {}
+key: '0000000000000000000000000000000000000000000000000000000000000000'
+prefix: hosts/{}/
+pool:
+  prefix: pool/
+"#,
+            store.path().display(),
+            host
+        ))
+        .unwrap()
+    }
+
+    fn exists(pool: &Client, name: &str) -> bool {
+        pool.list(name)
+            .unwrap()
+            .iter()
+            .any(|(found_name, _, _)| found_name == name)
+    }
+
+    #[test]
+    fn index_records_round_trip() {
+        let dir = tempdir().unwrap();
+        let conn = open_connection(&dir.path().join("manifest.db")).unwrap();
+
+        let mut index = Vec::new();
+        push_index_record(&mut index, &[1; DIGEST_LEN], 100, 0);
+        push_index_record(&mut index, &[2; DIGEST_LEN], 50, 100);
+        assert_eq!(index.len(), 2 * RECORD_LEN);
+
+        insert_index(&conn, "archive_a", "id_a", 120, &index).unwrap();
+
+        assert_eq!(
+            select_pool_block(&conn, &[1; DIGEST_LEN]).unwrap(),
+            Some(("archive_a".to_owned(), 0))
+        );
+        assert_eq!(
+            select_pool_block(&conn, &[2; DIGEST_LEN]).unwrap(),
+            Some(("archive_a".to_owned(), 100))
+        );
+        assert_eq!(select_pool_block(&conn, &[3; DIGEST_LEN]).unwrap(), None);
+    }
+
+    #[test]
+    fn malformed_index_is_rejected() {
+        let dir = tempdir().unwrap();
+        let conn = open_connection(&dir.path().join("manifest.db")).unwrap();
+
+        let mut index = Vec::new();
+        push_index_record(&mut index, &[1; DIGEST_LEN], 100, 0);
+        index.pop();
+
+        assert!(insert_index(&conn, "archive_a", "id_a", 120, &index).is_err());
+        assert!(select_pool_archive_names(&conn).unwrap().is_empty());
+    }
+
+    #[test]
+    fn archives_referenced_by_other_hosts_are_kept() {
+        let store = tempdir().unwrap();
+
+        let config_a = config(&store, "a");
+        let config_b = config(&store, "b");
+        let client_a = Client::new(&config_a).unwrap();
+        let client_b = Client::new(&config_b).unwrap();
+        let pool_a = client_a.pool().unwrap();
+        let pool_b = client_b.pool().unwrap();
+
+        let name = pool_archive_name().unwrap();
+
+        let b2_file_id = with_lock(&client_a, &config_a, |lock_a| {
+            let (b2_file_id, _) =
+                upload_pool_archive(&pool_a, lock_a, &name, 1, &b"data"[..], b"index")?;
+
+            upload_refs(&pool_a, lock_a, &config_a, from_ref(&name))?;
+
+            Ok(b2_file_id)
+        })
+        .unwrap();
+
+        with_lock(&client_b, &config_b, |lock_b| {
+            upload_refs(&pool_b, lock_b, &config_b, from_ref(&name))
+        })
+        .unwrap();
+
+        with_lock(&client_a, &config_a, |lock_a| {
+            remove_pool_archives(
+                &pool_a,
+                lock_a,
+                &config_a,
+                &[],
+                vec![(name.clone(), b2_file_id.clone())],
+            )
+        })
+        .unwrap();
+
+        assert!(!exists(&pool_a, &index_name(&name)));
+        assert!(exists(&pool_a, &name));
+        assert_eq!(read_refs(&pool_a).unwrap(), HashSet::from([name.clone()]));
+
+        with_lock(&client_b, &config_b, |lock_b| {
+            upload_refs(&pool_b, lock_b, &config_b, &[])
+        })
+        .unwrap();
+
+        with_lock(&client_a, &config_a, |lock_a| {
+            remove_pool_archives(
+                &pool_a,
+                lock_a,
+                &config_a,
+                &[],
+                vec![(name.clone(), b2_file_id)],
+            )
+        })
+        .unwrap();
+
+        assert!(!exists(&pool_a, &name));
+        assert!(read_refs(&pool_a).unwrap().is_empty());
+    }
+
+    #[test]
+    fn references_to_removed_indexes_are_rejected() {
+        let store = tempdir().unwrap();
+
+        let config = config(&store, "a");
+        let client = Client::new(&config).unwrap();
+        let pool = client.pool().unwrap();
+
+        let name = pool_archive_name().unwrap();
+
+        with_lock(&client, &config, |lock| {
+            upload_pool_archive(&pool, lock, &name, 1, &b"data"[..], b"index")
+        })
+        .unwrap();
+
+        let names = HashSet::from([name.clone()]);
+        verify_pool_archives(&pool, &names).unwrap();
+
+        let index_name = index_name(&name);
+        for (found_name, b2_file_id, _) in pool.list(&index_name).unwrap() {
+            pool.remove(&found_name, &b2_file_id).unwrap();
+        }
+
+        assert!(verify_pool_archives(&pool, &names).is_err());
+    }
+
+    #[test]
+    fn pending_archives_are_removed_with_their_index() {
+        let store = tempdir().unwrap();
+
+        let config = config(&store, "a");
+        let client = Client::new(&config).unwrap();
+        let pool = client.pool().unwrap();
+
+        let uploaded = pool_archive_name().unwrap();
+        let pending = pool_archive_name().unwrap();
+        let kept = pool_archive_name().unwrap();
+
+        with_lock(&client, &config, |lock| {
+            upload_pool_archive(&pool, lock, &uploaded, 1, &b"data"[..], b"index")?;
+            upload_pool_archive(&pool, lock, &kept, 1, &b"data"[..], b"index")?;
+
+            remove_pending_pool_archives(&pool, lock, &[uploaded.clone(), pending])
+        })
+        .unwrap();
+
+        assert!(!exists(&pool, &uploaded));
+        assert!(!exists(&pool, &index_name(&uploaded)));
+        assert!(exists(&pool, &kept));
+        assert!(exists(&pool, &index_name(&kept)));
+    }
+}
@@ -16,12 +16,47 @@ GNU General Public License for more details.
 You should have received a copy of the GNU General Public License
 along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
 */
-use std::io::Read;
+use std::fs::File;
+use std::io::{Read, Result as IoResult};
 use std::mem::replace;
 
+use nix::{
+    errno::Errno,
+    unistd::{lseek, Whence},
+};
+
 use super::Fallible;
 
-pub fn split(mut reader: impl Read, mut consumer: impl FnMut(&[u8]) -> Fallible) -> Fallible {
+pub fn data_extents(file: &File, len: u64) -> IoResult<Vec<(u64, u64)>> {
+    let mut extents = Vec::new();
+    let mut pos = 0;
+
+    while pos < len {
+        let start = match lseek(file, pos as _, Whence::SeekData) {
+            Ok(start) => start as u64,
+            Err(Errno::ENXIO) => break,
+            Err(Errno::EINVAL) if pos == 0 => return Ok(vec![(0, len)]),
+            Err(err) => return Err(err.into()),
+        };
+
+        let end = (lseek(file, start as _, Whence::SeekHole)? as u64).min(len);
+
+        if start < end {
+            extents.push((start, end));
+        }
+
+        pos = end.max(start + 1);
+    }
+
+    Ok(extents)
+}
+
+pub fn split(
+    mut reader: impl Read,
+    chunk_bits: u32,
+    mut consumer: impl FnMut(&[u8]) -> Fallible,
+) -> Fallible {
+    let chunk_mask = (1 << chunk_bits) - 1;
     let mut buf = Vec::new();
     let mut start = 0;
     let mut sum = RollingSum::new();
@@ -39,7 +74,7 @@ pub fn split(mut reader: impl Read, mut consumer: impl FnMut(&[u8]) -> Fallible)
             break;
         }
 
-        while let Some(pos) = sum.split(&buf[end..]) {
+        while let Some(pos) = sum.split(&buf[end..], chunk_mask) {
             end += pos;
 
             consumer(&buf[start..end])?;
@@ -73,7 +108,7 @@ impl RollingSum {
         }
     }
 
-    pub fn split(&mut self, buf: &[u8]) -> Option<usize> {
+    pub fn split(&mut self, buf: &[u8], chunk_mask: u32) -> Option<usize> {
         for (idx, &new_val) in buf.iter().enumerate() {
             let old_val = replace(&mut self.win[self.pos], new_val);
             self.pos = (self.pos + 1) & WINDOW_MASK;
@@ -85,7 +120,7 @@ impl RollingSum {
 
             let digest = (((self.s1 & 0xFFFF) as u32) << 16) | ((self.s2 & 0xFFFF) as u32);
 
-            if digest & CHUNK_MASK == CHUNK_MASK {
+            if digest & chunk_mask == chunk_mask {
                 return Some(idx + 1);
             }
         }
@@ -98,8 +133,4 @@ const WINDOW_BITS: usize = 6;
 const WINDOW_SIZE: usize = 1 << WINDOW_BITS;
 const WINDOW_MASK: usize = WINDOW_SIZE - 1;
 
-const CHUNK_BITS: u32 = 15;
-const CHUNK_SIZE: u32 = 1 << CHUNK_BITS;
-const CHUNK_MASK: u32 = CHUNK_SIZE - 1;
-
 const CHAR_OFFSET: usize = 31;
@@ -0,0 +1,110 @@
+/*
+Copyright 2019 Adam Reichold
+
+This file is part of b2_backup.
+
+b2_backup is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+b2_backup is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with b2_backup.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::ffi::CString;
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr::null_mut;
+
+use nix::libc::{lgetxattr, llistxattr, lsetxattr, ENODATA, ENOTSUP, ERANGE};
+
+pub type Xattrs = Vec<(Vec<u8>, Vec<u8>)>;
+
+pub fn read_xattrs(path: &Path) -> IoResult<Xattrs> {
+    let path = c_path(path)?;
+
+    let names = match read_buf(|buf, len| unsafe { llistxattr(path.as_ptr(), buf.cast(), len) }) {
+        Ok(names) => names,
+        Err(err) if err.raw_os_error() == Some(ENOTSUP) => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+
+    let mut xattrs = Vec::new();
+
+    for name in names
+        .split(|byte| *byte == 0)
+        .filter(|name| !name.is_empty())
+    {
+        let c_name = CString::new(name)?;
+
+        let value = match read_buf(|buf, len| unsafe {
+            lgetxattr(path.as_ptr(), c_name.as_ptr(), buf.cast(), len)
+        }) {
+            Ok(value) => value,
+            Err(err) if err.raw_os_error() == Some(ENODATA) => continue,
+            Err(err) => return Err(err),
+        };
+
+        xattrs.push((name.to_owned(), value));
+    }
+
+    Ok(xattrs)
+}
+
+pub fn write_xattr(path: &Path, name: &[u8], value: &[u8]) -> IoResult<()> {
+    let path = c_path(path)?;
+    let name = CString::new(name)?;
+
+    let res = unsafe {
+        lsetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    };
+
+    if res != 0 {
+        return Err(IoError::last_os_error());
+    }
+
+    Ok(())
+}
+
+fn read_buf(mut read: impl FnMut(*mut u8, usize) -> isize) -> IoResult<Vec<u8>> {
+    loop {
+        let len = read(null_mut(), 0);
+        if len < 0 {
+            return Err(IoError::last_os_error());
+        }
+
+        let mut buf = vec![0; len as usize];
+
+        let len = read(buf.as_mut_ptr(), buf.len());
+        if len < 0 {
+            let err = IoError::last_os_error();
+
+            if err.raw_os_error() == Some(ERANGE) {
+                continue;
+            }
+
+            return Err(err);
+        }
+
+        buf.truncate(len as usize);
+
+        return Ok(buf);
+    }
+}
+
+fn c_path(path: &Path) -> IoResult<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| IoError::new(ErrorKind::InvalidInput, err))
+}